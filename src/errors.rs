@@ -1,4 +1,4 @@
-use crate :: { import::*, IpcError };
+use crate :: { import::*, IpcError, RemoteError, ErrorCode };
 
 
 
@@ -42,9 +42,98 @@ pub enum EkkeIoError
 	//
 	DoubleServiceRegistration( String, String ),
 
+	#[ fail( display = "Rpc: The actor providing service {} has stopped", _0 ) ]
+	//
+	ServiceUnavailable( String ),
+
+	#[ fail( display = "Rpc: Actor {} does not provide service {}", _0, _1 ) ]
+	//
+	NotServiceProvider( String, String ),
+
+	#[ fail( display = "Rpc: Service is overloaded, try again later: {}", _0 ) ]
+	//
+	Overloaded( String ),
+
+	#[ fail( display = "Rpc: Too many requests, rate limit exceeded for service: {}", _0 ) ]
+	//
+	RateLimited( String ),
+
+	#[ fail( display = "Rpc: Permission denied for service: {}", _0 ) ]
+	//
+	PermissionDenied( String ),
+
 	#[ fail( display = "Rpc: Peer failed to handle request: [{}].", _0 ) ]
 	//
-	IpcError( String ),
+	IpcError( RemoteError ),
+
+	#[ fail( display = "Rpc: No version of service {} within {} is available, we have: {}", _0, _1, _2 ) ]
+	//
+	VersionMismatch( String, String, String ),
+
+	#[ fail( display = "Rpc: Could not deserialize the response: {}", _0 ) ]
+	//
+	InvalidResponse( String ),
+
+	#[ fail( display = "{} is shutting down", _0 ) ]
+	//
+	ShuttingDown( String ),
+
+	#[ fail( display = "IpcPeer: the write queue is full" ) ]
+	//
+	QueueFull,
+
+	#[ fail( display = "IpcPeer: the connection is closed" ) ]
+	//
+	ConnectionClosed,
+
+	#[ fail( display = "Rpc: no response for service {} in time", _0 ) ]
+	//
+	Timeout( String ),
+
+	#[ fail( display = "IpcPeer: frame of {} bytes exceeds the maximum of {} bytes", _0, _1 ) ]
+	//
+	FrameTooBig( usize, usize ),
+
+	#[ fail( display = "IpcPeer: payload of {} bytes exceeds the maximum of {} bytes", _0, _1 ) ]
+	//
+	PayloadTooBig( usize, usize ),
+
+	#[ fail( display = "IpcPeer: payload is nested deeper than the maximum of {} levels", _0 ) ]
+	//
+	NestingTooDeep( usize ),
+
+	#[ fail( display = "IpcPeer: received a malformed frame: {}", _0 ) ]
+	//
+	MalformedFrame( String ),
+
+	#[ fail( display = "IpcPeer: failed to encode a frame: {}", _0 ) ]
+	//
+	EncodeFailed( String ),
+
+	#[ fail( display = "IpcPeer: the peer is not compatible: {}", _0 ) ]
+	//
+	IncompatiblePeer( String ),
+
+	#[ fail( display = "Encrypted session failed: {}", _0 ) ]
+	//
+	Encryption( String ),
+
+	#[ fail( display = "The peer authenticated with a key we don't trust" ) ]
+	//
+	UntrustedPeer,
+
+	#[ fail( display = "IpcPeer: io error on the connection: {}", _0 ) ]
+	//
+	Io( #[ cause ] std::io::Error ),
+}
+
+
+impl From< std::io::Error > for EkkeIoError
+{
+	fn from( err: std::io::Error ) -> Self
+	{
+		EkkeIoError::Io( err )
+	}
 }
 
 
@@ -52,11 +141,19 @@ impl From< IpcError > for EkkeIoError
 {
 	fn from( err: IpcError ) -> Self
 	{
-		let de: String = match des( &err.ipc_msg.payload )
+		let service = err.ipc_msg.service;
+		let payload = &err.ipc_msg.payload;
+
+		// Older peers send a bare string.
+		//
+		let de = des::<RemoteError>( payload ).unwrap_or_else( |_|
 		{
-			Ok ( msg ) => msg                                        ,
-			Err( _   ) => "Failed to deserialize Error Message".into(),
-		};
+			match des::<String>( payload )
+			{
+				Ok ( msg ) => RemoteError::new( ErrorCode::Internal, service, msg                                   ),
+				Err( _   ) => RemoteError::new( ErrorCode::Internal, service, "Failed to deserialize Error Message" ),
+			}
+		});
 
 		EkkeIoError::IpcError( de )
 	}
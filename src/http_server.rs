@@ -1,6 +1,16 @@
-use crate :: { import::*, Rpc };
+use crate :: { import::*, Rpc, IpcPeer };
 
-use hyper:: { Body, Request, Response, Server, service::service_fn };
+use hyper            :: { Body, Request, Response, Server, StatusCode, service::service_fn, header::{ self, HeaderValue }, upgrade::Upgraded };
+use tokio_tungstenite:: { WebSocketStream, tungstenite::protocol::Role                                                                       };
+
+#[ cfg( feature = "noise" ) ]
+//
+use crate :: { NoiseConfig, NoiseStream };
+
+
+mod ws_stream;
+
+pub use ws_stream::WsStream;
 
 
 // using this and -> impl ResponseFuture doesn't work as long as we need the compat
@@ -11,6 +21,18 @@ pub type ResponseFuture = Pin< Box< dyn StdFuture< Output = Result< Response<Bod
 pub type Responder      = Box< Fn( Request<Body>, Addr<Rpc>, Logger ) -> ResponseFuture + Send + Sync + 'static >;
 
 
+/// The only websocket version there is, RFC 6455.
+///
+const WS_VERSION: &str = "13";
+
+
+/// Serves http requests with the user supplied responder. Requests that ask for a websocket upgrade are
+/// handled by the server itself: every websocket becomes an [`IpcPeer`](struct.IpcPeer.html) connected to
+/// the rpc, so browser frontends can call registered services directly with binary cbor frames.
+///
+/// With the `noise` feature, [`with_noise`](#method.with_noise) makes every websocket run a noise handshake
+/// before the IpcPeer is created, so only clients with a trusted key get to talk to the rpc.
+///
 #[ derive( Clone ) ]
 //
 pub struct HttpServer
@@ -18,6 +40,10 @@ pub struct HttpServer
 	log    : Logger          ,
 	handler: Arc< Responder >,
 	rpc    : Addr<Rpc>       ,
+
+	#[ cfg( feature = "noise" ) ]
+	//
+	noise  : Option< NoiseConfig >,
 }
 
 
@@ -30,10 +56,26 @@ impl HttpServer
 			log,
 			rpc,
 			handler: Arc::new( handler ),
+
+			#[ cfg( feature = "noise" ) ]
+			//
+			noise: None,
 		}
 	}
 
 
+	/// Encrypt websockets with noise. Clients have to run the handshake with
+	/// [`NoiseStream::connect`](struct.NoiseStream.html#method.connect) over the websocket before talking to the rpc.
+	///
+	#[ cfg( feature = "noise" ) ]
+	//
+	pub fn with_noise( mut self, config: NoiseConfig ) -> Self
+	{
+		self.noise = Some( config );
+		self
+	}
+
+
 	pub async fn run( &self, addr: SocketAddr )
 	{
 		info!( self.log, "Listening on http://{}", addr );
@@ -53,11 +95,24 @@ impl HttpServer
 			//
 			.serve( ||
 			{
-				let cb  = self.handler.clone()                             ;
-				let rpc = self.rpc    .clone()                             ;
-				let log = self.log    .new( o!( "fn" => "http_closure" ) ) ;
+				let cb     = self.handler.clone()                             ;
+				let rpc    = self.rpc    .clone()                             ;
+				let log    = self.log    .new( o!( "fn" => "http_closure" ) ) ;
+				let server = self.clone()                                     ;
 
-				service_fn( move |req| cb( req, rpc.clone(), log.clone() ).compat() )
+				service_fn( move |req|
+				{
+					if Self::is_websocket_upgrade( &req )
+					{
+						let resp = server.websocket_upgrade( req, log.clone() );
+
+						let fut: ResponseFuture = Box::pin( async move { Ok( resp ) } );
+
+						return fut.compat();
+					}
+
+					cb( req, rpc.clone(), log.clone() ).compat()
+				})
 
 			});
 
@@ -69,4 +124,201 @@ impl HttpServer
 			error!( self.log, "server error: {}", e );
 		}
 	}
+
+
+
+	/// Whether the client asks to upgrade this connection to a websocket.
+	///
+	fn is_websocket_upgrade( req: &Request<Body> ) -> bool
+	{
+		let has_token = |name, token: &str| req.headers().get_all( name ).iter()
+
+			.filter_map( |v| v.to_str().ok() )
+			.any( |v| v.split( ',' ).any( |t| t.trim().eq_ignore_ascii_case( token ) ) )
+		;
+
+		has_token( header::CONNECTION, "upgrade" ) && has_token( header::UPGRADE, "websocket" )
+	}
+
+
+
+	/// Answer the handshake and once hyper hands us the upgraded connection, create an IpcPeer for it.
+	///
+	fn websocket_upgrade( &self, req: Request<Body>, log: Logger ) -> Response<Body>
+	{
+		// RFC 6455, section 4.4: tell the client which version we speak.
+		//
+		if !Self::version_supported( &req )
+		{
+			warn!( log, "Websocket upgrade request with unsupported Sec-WebSocket-Version: {:?}", req.headers().get( header::SEC_WEBSOCKET_VERSION ) );
+
+			let mut resp = Response::new( Body::empty() );
+			*resp.status_mut() = StatusCode::UPGRADE_REQUIRED;
+
+			resp.headers_mut().insert( header::SEC_WEBSOCKET_VERSION, HeaderValue::from_static( WS_VERSION ) );
+
+			return resp;
+		}
+
+		let accept = match req.headers().get( header::SEC_WEBSOCKET_KEY )
+		{
+			Some( key ) => Self::accept_key( key.as_bytes() ),
+
+			None =>
+			{
+				warn!( log, "Websocket upgrade request without Sec-WebSocket-Key" );
+
+				let mut resp = Response::new( Body::empty() );
+				*resp.status_mut() = StatusCode::BAD_REQUEST;
+
+				return resp;
+			}
+		};
+
+		let peer_log = log.new( o!( "Actor" => "IpcPeer", "transport" => "websocket" ) );
+		let err_log  = log.clone();
+		let server   = self.clone();
+
+		Arbiter::spawn
+		(
+			req.into_body().on_upgrade()
+
+				.map( move |upgraded|
+				{
+					let stream = WsStream::new( WebSocketStream::from_raw_socket( upgraded, Role::Server, None ) );
+
+					server.connect( stream, peer_log );
+				})
+
+				.map_err( move |e| error!( err_log, "Websocket upgrade failed: {}", e ) )
+		);
+
+
+		let mut resp = Response::new( Body::empty() );
+		*resp.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+
+		let headers = resp.headers_mut();
+
+		headers.insert( header::UPGRADE             , HeaderValue::from_static( "websocket" ) );
+		headers.insert( header::CONNECTION          , HeaderValue::from_static( "Upgrade"   ) );
+		headers.insert( header::SEC_WEBSOCKET_ACCEPT, accept                                  );
+
+		resp
+	}
+
+
+
+	/// Create the IpcPeer for an upgraded websocket, after the noise handshake if we have a noise config.
+	///
+	fn connect( &self, stream: WsStream<Upgraded>, log: Logger )
+	{
+		let rpc = self.rpc.clone();
+
+		#[ cfg( feature = "noise" ) ]
+		//
+		{
+			if let Some( config ) = self.noise.clone()
+			{
+				Arbiter::spawn( async move
+				{
+					match await!( NoiseStream::accept( stream, config ) )
+					{
+						Ok ( stream ) => { IpcPeer::create( |ctx| IpcPeer::new( stream, rpc, ctx.address(), log ) ); }
+						Err( error  ) => warn!( log, "Websocket: noise handshake failed: {}", error ),
+					}
+
+					Ok(())
+
+				}.boxed().compat());
+
+				return;
+			}
+		}
+
+		IpcPeer::create( |ctx| IpcPeer::new( stream, rpc, ctx.address(), log ) );
+	}
+
+
+
+	/// Whether the client speaks the websocket version we do.
+	///
+	fn version_supported( req: &Request<Body> ) -> bool
+	{
+		req.headers().get( header::SEC_WEBSOCKET_VERSION ).map( |v| v == WS_VERSION ).unwrap_or( false )
+	}
+
+
+
+	/// Compute Sec-WebSocket-Accept as described in RFC 6455, section 4.2.2.
+	///
+	fn accept_key( key: &[u8] ) -> HeaderValue
+	{
+		const GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+		let mut sha = sha1::Sha1::new();
+
+		sha.update( key  );
+		sha.update( GUID );
+
+		// base64 only produces valid header characters
+		//
+		HeaderValue::from_str( &base64::encode( &sha.digest().bytes() ) ).unwrap()
+	}
+}
+
+
+
+#[ cfg( test ) ]
+//
+mod tests
+{
+	use super::*;
+
+
+	fn upgrade_request( version: Option<&str> ) -> Request<Body>
+	{
+		let mut builder = Request::builder();
+
+		builder
+			.header( header::CONNECTION       , "keep-alive, Upgrade"      )
+			.header( header::UPGRADE          , "websocket"                )
+			.header( header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==" )
+		;
+
+		if let Some( version ) = version
+		{
+			builder.header( header::SEC_WEBSOCKET_VERSION, version );
+		}
+
+		builder.body( Body::empty() ).unwrap()
+	}
+
+
+	// The example from RFC 6455, section 1.3.
+	//
+	#[ test ]
+	//
+	fn accept_key()
+	{
+		assert_eq!( HttpServer::accept_key( b"dGhlIHNhbXBsZSBub25jZQ==" ), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=" );
+	}
+
+
+	#[ test ]
+	//
+	fn detects_upgrade()
+	{
+		assert!( HttpServer::is_websocket_upgrade( &upgrade_request( Some( "13" ) ) ) );
+		assert!( !HttpServer::is_websocket_upgrade( &Request::new( Body::empty() ) ) );
+	}
+
+
+	#[ test ]
+	//
+	fn version()
+	{
+		assert!(  HttpServer::version_supported( &upgrade_request( Some( "13" ) ) ) );
+		assert!( !HttpServer::version_supported( &upgrade_request( Some( "8"  ) ) ) );
+		assert!( !HttpServer::version_supported( &upgrade_request( None         ) ) );
+	}
 }
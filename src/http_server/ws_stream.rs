@@ -0,0 +1,150 @@
+use crate :: { import::* };
+
+use std              :: { io, collections::VecDeque                           };
+use tokio::prelude   :: { Async, AsyncSink, Poll, Sink                        };
+use tokio_tungstenite:: { WebSocketStream, tungstenite::{ Message, Error as WsError } };
+
+
+/// Turns a websocket connection into a byte stream so it can be handed to [`IpcPeer`](struct.IpcPeer.html)
+/// like any other AsyncRead + AsyncWrite, directly or wrapped in a [`NoiseStream`](struct.NoiseStream.html).
+/// Every write becomes a binary websocket frame of it's own. The bytes aren't looked at, so a frame holds whatever
+/// the layer above wrote at once, eg. one or more encoded IpcMessages, which a browser reads as a cbor sequence.
+/// Incoming binary frames are concatenated.
+///
+/// Text frames are refused, ping/pong are answered by tungstenite.
+///
+pub struct WsStream<S>
+
+	where S: AsyncRead + AsyncWrite
+
+{
+	inner   : WebSocketStream<S>  ,
+	incoming: Vec<u8>             ,
+	read_pos: usize               ,
+	outgoing: VecDeque< Vec<u8> > ,
+}
+
+
+impl<S> WsStream<S>
+
+	where S: AsyncRead + AsyncWrite
+
+{
+	pub fn new( inner: WebSocketStream<S> ) -> Self
+	{
+		Self { inner, incoming: Vec::new(), read_pos: 0, outgoing: VecDeque::new() }
+	}
+
+
+	fn io_err( error: WsError ) -> io::Error
+	{
+		match error
+		{
+			WsError::Io( e ) => e,
+			e                => io::Error::new( io::ErrorKind::Other, e ),
+		}
+	}
+}
+
+
+
+impl<S> io::Read for WsStream<S>
+
+	where S: AsyncRead + AsyncWrite
+
+{
+	fn read( &mut self, buf: &mut [u8] ) -> io::Result<usize>
+	{
+		loop
+		{
+			if self.read_pos < self.incoming.len()
+			{
+				let available = &self.incoming[ self.read_pos.. ];
+				let n         = available.len().min( buf.len() );
+
+				buf[ ..n ].copy_from_slice( &available[ ..n ] );
+				self.read_pos += n;
+
+				return Ok( n );
+			}
+
+
+			match self.inner.poll().map_err( Self::io_err )?
+			{
+				Async::Ready( Some( Message::Binary( data ) ) ) =>
+				{
+					self.incoming = data;
+					self.read_pos = 0;
+				}
+
+				Async::Ready( Some( Message::Text( _ ) ) ) =>
+
+					return Err( io::Error::new( io::ErrorKind::InvalidData, "WsStream: only binary frames are supported" ) ),
+
+				// Ping and Pong are handled by tungstenite
+				//
+				Async::Ready( Some( Message::Ping(_) ) ) |
+				Async::Ready( Some( Message::Pong(_) ) ) => continue,
+
+				Async::Ready( Some( Message::Close(_) ) ) |
+				Async::Ready( None                     ) => return Ok( 0 ),
+
+				Async::NotReady => return Err( io::ErrorKind::WouldBlock.into() ),
+			}
+		}
+	}
+}
+
+
+
+impl<S> io::Write for WsStream<S>
+
+	where S: AsyncRead + AsyncWrite
+
+{
+	fn write( &mut self, buf: &[u8] ) -> io::Result<usize>
+	{
+		if !buf.is_empty() { self.outgoing.push_back( buf.to_vec() ); }
+
+		Ok( buf.len() )
+	}
+
+
+	fn flush( &mut self ) -> io::Result<()>
+	{
+		while let Some( data ) = self.outgoing.pop_front()
+		{
+			if let AsyncSink::NotReady( frame ) = self.inner.start_send( Message::Binary( data ) ).map_err( Self::io_err )?
+			{
+				if let Message::Binary( data ) = frame
+				{
+					self.outgoing.push_front( data );
+				}
+
+				return Err( io::ErrorKind::WouldBlock.into() );
+			}
+		}
+
+		match self.inner.poll_complete().map_err( Self::io_err )?
+		{
+			Async::Ready(_) => Ok(()),
+			Async::NotReady => Err( io::ErrorKind::WouldBlock.into() ),
+		}
+	}
+}
+
+
+
+impl<S> AsyncRead for WsStream<S> where S: AsyncRead + AsyncWrite {}
+
+
+impl<S> AsyncWrite for WsStream<S>
+
+	where S: AsyncRead + AsyncWrite
+
+{
+	fn shutdown( &mut self ) -> Poll<(), io::Error>
+	{
+		self.inner.close().map_err( Self::io_err )
+	}
+}
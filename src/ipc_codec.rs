@@ -0,0 +1,622 @@
+//! The codec IpcPeer uses on the wire. It wraps the cbor codec and refuses frames that go over
+//! the limits in IpcPeerConfig, so a peer can't make us buffer or allocate without bound.
+//! It also takes care of payload compression once it has been negotiated in the handshake, and puts
+//! messages that were sent in chunks back together.
+//
+use crate :: { import::*, IpcMessage, IpcPeerConfig, EkkeIoError, Handshake, MessageType, Chunk, ConnID };
+
+use bytes :: { BytesMut };
+
+
+/// Cbor codec for IpcMessage that enforces maximum frame and payload sizes and a maximum nesting
+/// depth for the payload.
+///
+/// A frame that can't be decoded is fatal: cbor has no marker where the next frame starts, so we can't find our
+/// way back in the stream. The read buffer is cleared and IpcPeer closes the connection. Frames with a payload over
+/// our limits are consumed whole, so they can be skipped.
+///
+/// Payloads of at least compression_threshold bytes are compressed with zstd once `compress` is set,
+/// which IpcPeer does when both sides agreed on it. Compressed payloads are accepted as soon as we offered
+/// compression, since the peer may start using it before our side processed it's handshake.
+///
+/// Chunks are buffered until the last one came in, then the whole message is decoded and checked against
+/// the same limits as any other frame. A message in chunks can't be bigger than max_frame_size either.
+///
+#[ derive( Debug ) ]
+//
+pub(crate) struct IpcCodec
+{
+	inner           : Codec<IpcMessage, IpcMessage>,
+	max_frame_size  : usize                        ,
+	max_payload_size: usize                        ,
+	max_nesting     : usize                        ,
+	accept_zstd     : bool                         ,
+	compress        : Arc<AtomicBool>              ,
+	threshold       : usize                        ,
+	chunks          : HashMap< ConnID, Vec<u8> >   ,
+}
+
+
+/// How many messages a peer can be sending us in chunks at the same time.
+///
+const MAX_CHUNKED: usize = 8;
+
+
+impl IpcCodec
+{
+	pub(crate) fn new( config: &IpcPeerConfig, offered: &Handshake, compress: Arc<AtomicBool> ) -> Self
+	{
+		Self
+		{
+			inner           : Codec::new().packed( true )                         ,
+			max_frame_size  : config.max_frame_size                               ,
+			max_payload_size: config.max_payload_size                             ,
+			max_nesting     : config.max_nesting                                  ,
+			accept_zstd     : offered.compression.iter().any( |c| c == "zstd" )   ,
+			compress                                                              ,
+			threshold       : config.compression_threshold                        ,
+			chunks          : HashMap::new()                                      ,
+		}
+	}
+}
+
+
+
+impl Decoder for IpcCodec
+{
+	type Item  = IpcMessage ;
+	type Error = EkkeIoError;
+
+
+	fn decode( &mut self, src: &mut BytesMut ) -> Result< Option<Self::Item>, Self::Error >
+	{
+		// A chunk on it's own isn't a message, so keep going until we have a whole one or run out of bytes.
+		//
+		loop
+		{
+			let frame = match self.frame( src )?
+			{
+				Some( frame ) => frame,
+				None          => return Ok( None ),
+			};
+
+			if frame.ms_type != MessageType::Chunk { return Ok( Some( frame ) ); }
+
+			if let Some( msg ) = self.reassemble( frame )?
+			{
+				return Ok( Some( msg ) );
+			}
+		}
+	}
+}
+
+
+
+impl IpcCodec
+{
+	/// Decode a single frame.
+	///
+	fn frame( &mut self, src: &mut BytesMut ) -> Result< Option<IpcMessage>, EkkeIoError >
+	{
+		let available = src.len();
+
+		let msg = match self.inner.decode( src )
+		{
+			Ok( Some( msg ) ) => msg,
+
+			// If we can't decode a frame yet, everything in the buffer belongs to one incomplete frame.
+			//
+			Ok( None ) =>
+			{
+				if available > self.max_frame_size
+				{
+					src.clear();
+					return Err( EkkeIoError::FrameTooBig( available, self.max_frame_size ) );
+				}
+
+				return Ok( None );
+			}
+
+			Err( error ) =>
+			{
+				src.clear();
+				return Err( EkkeIoError::MalformedFrame( error.to_string() ) );
+			}
+		};
+
+
+		let frame_size = available - src.len();
+
+		if frame_size > self.max_frame_size
+		{
+			return Err( EkkeIoError::FrameTooBig( frame_size, self.max_frame_size ) );
+		}
+
+		self.check( msg ).map( Some )
+	}
+
+
+	/// Check the payload of a decoded message against our limits and decompress it.
+	///
+	fn check( &self, mut msg: IpcMessage ) -> Result< IpcMessage, EkkeIoError >
+	{
+		if msg.payload.len() > self.max_payload_size
+		{
+			return Err( EkkeIoError::PayloadTooBig( msg.payload.len(), self.max_payload_size ) );
+		}
+
+		if msg.compressed
+		{
+			if !self.accept_zstd
+			{
+				return Err( EkkeIoError::MalformedFrame( "compressed payload, but we didn't offer compression".into() ) );
+			}
+
+			msg.payload    = decompress( &msg.payload, self.max_payload_size )?;
+			msg.compressed = false;
+		}
+
+		if !msg.payload.is_empty()
+		{
+			check_nesting( &msg.payload, self.max_nesting )?;
+		}
+
+		Ok( msg )
+	}
+
+
+	/// Add a chunk to the message it belongs to. Returns the message once the last chunk came in.
+	///
+	fn reassemble( &mut self, frame: IpcMessage ) -> Result< Option<IpcMessage>, EkkeIoError >
+	{
+		let chunk: Chunk = serde_cbor::from_slice( &frame.payload )
+
+			.map_err( |e| EkkeIoError::MalformedFrame( format!( "invalid chunk: {}", e ) ) )?
+		;
+
+		if !self.chunks.contains_key( &frame.conn_id ) && self.chunks.len() >= MAX_CHUNKED
+		{
+			return Err( EkkeIoError::MalformedFrame( format!( "more than {} messages in chunks at once", MAX_CHUNKED ) ) );
+		}
+
+		let buffer = self.chunks.entry( frame.conn_id ).or_insert_with( Vec::new );
+
+		buffer.extend_from_slice( &chunk.data );
+
+		if buffer.len() > self.max_frame_size
+		{
+			let size = buffer.len();
+
+			self.chunks.remove( &frame.conn_id );
+
+			return Err( EkkeIoError::FrameTooBig( size, self.max_frame_size ) );
+		}
+
+		if !chunk.last { return Ok( None ); }
+
+
+		let data = self.chunks.remove( &frame.conn_id ).unwrap_or_default();
+
+		let msg: IpcMessage = serde_cbor::from_slice( &data )
+
+			.map_err( |e| EkkeIoError::MalformedFrame( format!( "invalid message in chunks: {}", e ) ) )?
+		;
+
+		if msg.ms_type == MessageType::Chunk
+		{
+			return Err( EkkeIoError::MalformedFrame( "nested chunks".into() ) );
+		}
+
+		self.check( msg ).map( Some )
+	}
+}
+
+
+
+impl Encoder for IpcCodec
+{
+	type Item  = IpcMessage ;
+	type Error = EkkeIoError;
+
+
+	fn encode( &mut self, mut item: Self::Item, dst: &mut BytesMut ) -> Result< (), Self::Error >
+	{
+		if self.compress.load( Ordering::SeqCst ) && item.payload.len() >= self.threshold
+		{
+			let packed = compress( &item.payload )?;
+
+			// Not everything compresses.
+			//
+			if packed.len() < item.payload.len()
+			{
+				item.payload    = packed;
+				item.compressed = true;
+			}
+		}
+
+		self.inner.encode( item, dst ).map_err( |e| EkkeIoError::EncodeFailed( e.to_string() ) )
+	}
+}
+
+
+
+#[ cfg( feature = "compression" ) ]
+//
+fn compress( data: &[u8] ) -> Result< Vec<u8>, EkkeIoError >
+{
+	Ok( zstd::encode_all( data, 0 )? )
+}
+
+
+/// Compression is never negotiated without the feature, so this isn't called.
+///
+#[ cfg( not( feature = "compression" ) ) ]
+//
+fn compress( data: &[u8] ) -> Result< Vec<u8>, EkkeIoError >
+{
+	Ok( data.to_vec() )
+}
+
+
+/// Decompress, but never more than max bytes, so a small frame can't blow up in our face.
+///
+#[ cfg( feature = "compression" ) ]
+//
+fn decompress( data: &[u8], max: usize ) -> Result< Vec<u8>, EkkeIoError >
+{
+	use std::io::Read;
+
+	let mut out     = Vec::new();
+	let     decoder = zstd::stream::read::Decoder::new( data )?;
+
+	decoder.take( max as u64 + 1 ).read_to_end( &mut out )?;
+
+	if out.len() > max
+	{
+		return Err( EkkeIoError::PayloadTooBig( out.len(), max ) );
+	}
+
+	Ok( out )
+}
+
+
+#[ cfg( not( feature = "compression" ) ) ]
+//
+fn decompress( _data: &[u8], _max: usize ) -> Result< Vec<u8>, EkkeIoError >
+{
+	Err( EkkeIoError::MalformedFrame( "compressed payload, but compression is not supported".into() ) )
+}
+
+
+
+/// Walk the cbor in data without deserializing it and fail if containers are nested deeper than max.
+/// Tags count as a level of nesting, as they wrap the next item.
+///
+fn check_nesting( data: &[u8], max: usize ) -> Result< (), EkkeIoError >
+{
+	walk( data, max ).map( |_| () )
+}
+
+
+/// Walk the first cbor item in data and return where it ends.
+///
+fn walk( data: &[u8], max: usize ) -> Result< usize, EkkeIoError >
+{
+	let malformed = || EkkeIoError::MalformedFrame( "invalid cbor in payload".into() );
+
+	// For every open container, how many items are still to come. None for indefinite length.
+	//
+	let mut stack: Vec< Option<u64> > = Vec::new();
+	let mut pos = 0;
+
+	loop
+	{
+		let initial = *data.get( pos ).ok_or_else( malformed )?;
+		pos += 1;
+
+		let major = initial >> 5;
+		let info  = initial & 0x1f;
+
+		// A break ends an indefinite length container.
+		//
+		if initial == 0xff
+		{
+			match stack.pop()
+			{
+				Some( None ) => {}
+				_            => return Err( malformed() ),
+			}
+		}
+
+		else
+		{
+			let arg = match info
+			{
+				0..=23 => Some( u64::from( info ) ),
+
+				24..=27 =>
+				{
+					let len   = 1 << ( info - 24 );
+					let bytes = data.get( pos..pos + len ).ok_or_else( malformed )?;
+
+					pos += len;
+
+					Some( bytes.iter().fold( 0, |acc, b| acc << 8 | u64::from( *b ) ) )
+				}
+
+				31 => None,
+				_  => return Err( malformed() ),
+			};
+
+
+			let children = match ( major, arg )
+			{
+				// Integers and simple values, the argument was all there is.
+				//
+				( 0, Some(_) ) | ( 1, Some(_) ) | ( 7, Some(_) ) => Some( 0 ),
+
+				// Definite length byte and text strings.
+				//
+				( 2, Some( len ) ) | ( 3, Some( len ) ) =>
+				{
+					pos = usize::try_from( len ).ok()
+
+						.and_then( |len| pos.checked_add( len ) )
+						.filter  ( |end| *end <= data.len()     )
+						.ok_or_else( malformed )?
+					;
+
+					Some( 0 )
+				}
+
+				// Indefinite strings are a sequence of chunks ending in a break.
+				//
+				( 2, None ) | ( 3, None ) | ( 4, None ) | ( 5, None ) => None,
+
+				( 4, Some( len ) ) => Some( len ),
+				( 5, Some( len ) ) => Some( len.checked_mul( 2 ).ok_or_else( malformed )? ),
+				( 6, Some( _   ) ) => Some( 1 ),
+
+				_ => return Err( malformed() ),
+			};
+
+
+			if children != Some( 0 )
+			{
+				if stack.len() >= max
+				{
+					return Err( EkkeIoError::NestingTooDeep( max ) );
+				}
+
+				stack.push( children );
+				continue;
+			}
+		}
+
+
+		// An item is complete, count it against the containers it finishes.
+		//
+		loop
+		{
+			match stack.last_mut()
+			{
+				None                => return Ok( pos ),
+				Some( None        ) => break        ,
+				Some( Some( left )) =>
+				{
+					*left -= 1;
+
+					if *left > 0 { break; }
+
+					stack.pop();
+				}
+			}
+		}
+	}
+}
+
+
+
+#[ cfg( test ) ]
+//
+mod tests
+{
+	use super::*;
+	use crate :: { MessageType, ConnID, Batch };
+
+	use serde_bytes :: { ByteBuf };
+
+
+	fn codec( config: IpcPeerConfig ) -> IpcCodec
+	{
+		IpcCodec::new( &config, &Handshake::ours(), Arc::new( AtomicBool::new( false ) ) )
+	}
+
+
+	fn encode( payload: impl Serialize ) -> BytesMut
+	{
+		let msg     = IpcMessage::new( "service".into(), payload, MessageType::IpcRequestIn, ConnID::new() );
+		let mut buf = BytesMut::new();
+
+		codec( IpcPeerConfig::default() ).encode( msg, &mut buf ).unwrap();
+
+		buf
+	}
+
+
+	#[ test ]
+	//
+	fn round_trip()
+	{
+		let mut buf = encode( "hello" );
+
+		let msg = codec( IpcPeerConfig::default() ).decode( &mut buf ).unwrap().unwrap();
+
+		assert_eq!( msg.service, "service" );
+		assert_eq!( des::<String>( &msg.payload ).unwrap(), "hello" );
+		assert!   ( buf.is_empty() );
+	}
+
+
+	#[ test ]
+	//
+	fn batch_round_trip()
+	{
+		let requests: Vec<IpcMessage> = ( 0..3 ).map( |i|
+
+			IpcMessage::new( "service".into(), i, MessageType::IpcRequestIn, ConnID::new() )
+
+		).collect();
+
+		let batch     = Batch { messages: requests.clone(), batch_responses: true };
+		let msg       = IpcMessage::new( String::new(), batch, MessageType::Batch, ConnID::new() );
+		let mut buf   = BytesMut::new();
+		let mut codec = codec( IpcPeerConfig::default() );
+
+		codec.encode( msg, &mut buf ).unwrap();
+
+		let frame = codec.decode( &mut buf ).unwrap().unwrap();
+		let batch = des::<Batch>( &frame.payload ).unwrap();
+
+		assert_eq!( frame.ms_type, MessageType::Batch );
+		assert!   ( batch.batch_responses );
+		assert_eq!( batch.messages.len(), 3 );
+
+		for ( sent, got ) in requests.iter().zip( batch.messages.iter() )
+		{
+			assert_eq!( got.conn_id, sent.conn_id );
+			assert_eq!( des::<i32>( &got.payload ).unwrap(), des::<i32>( &sent.payload ).unwrap() );
+		}
+	}
+
+
+	// The whole frame is consumed, so the next one can still be decoded.
+	//
+	#[ test ]
+	//
+	fn payload_too_big_is_skipped()
+	{
+		let mut config = IpcPeerConfig::default();
+		config.max_payload_size = 16;
+
+		let mut codec = codec( config );
+		let mut buf   = encode( vec![ 0u8; 100 ] );
+
+		buf.extend_from_slice( &encode( "small" ) );
+
+		match codec.decode( &mut buf )
+		{
+			Err( EkkeIoError::PayloadTooBig( _, 16 ) ) => {}
+			other                                      => panic!( "expected PayloadTooBig, got: {:?}", other ),
+		}
+
+		let msg = codec.decode( &mut buf ).unwrap().unwrap();
+
+		assert_eq!( des::<String>( &msg.payload ).unwrap(), "small" );
+	}
+
+
+	#[ test ]
+	//
+	fn nesting_too_deep()
+	{
+		let mut config = IpcPeerConfig::default();
+		config.max_nesting = 3;
+
+		let mut buf = encode( vec![ vec![ vec![ vec![ vec![ 1 ] ] ] ] ] );
+
+		match codec( config ).decode( &mut buf )
+		{
+			Err( EkkeIoError::NestingTooDeep( 3 ) ) => {}
+			other                                   => panic!( "expected NestingTooDeep, got: {:?}", other ),
+		}
+	}
+
+
+	// We can't know how long an incomplete frame is going to be, but we don't buffer more than a frame.
+	//
+	#[ test ]
+	//
+	fn incomplete_frame_too_big()
+	{
+		let mut config = IpcPeerConfig::default();
+		config.max_frame_size = 32;
+
+		let     full = encode( vec![ 0u8; 100 ] );
+		let mut buf  = BytesMut::from( &full[ ..50 ] );
+
+		match codec( config ).decode( &mut buf )
+		{
+			Err( EkkeIoError::FrameTooBig( 50, 32 ) ) => {}
+			other                                     => panic!( "expected FrameTooBig, got: {:?}", other ),
+		}
+	}
+
+
+	#[ test ]
+	//
+	fn garbage_is_malformed()
+	{
+		let mut buf = BytesMut::from( &[ 0xff, 0xff, 0xff, 0xff ][..] );
+
+		match codec( IpcPeerConfig::default() ).decode( &mut buf )
+		{
+			Err( EkkeIoError::MalformedFrame( _ ) ) => {}
+			other                                   => panic!( "expected MalformedFrame, got: {:?}", other ),
+		}
+
+		assert!( buf.is_empty() );
+	}
+
+
+	fn chunk( stream: ConnID, size: usize, last: bool ) -> BytesMut
+	{
+		let chunk   = Chunk { data: ByteBuf::from( vec![ 0u8; size ] ), last };
+		let msg     = IpcMessage::new( String::new(), chunk, MessageType::Chunk, stream );
+		let mut buf = BytesMut::new();
+
+		codec( IpcPeerConfig::default() ).encode( msg, &mut buf ).unwrap();
+
+		buf
+	}
+
+
+	// Chunks can't be used to send a message bigger than a frame.
+	//
+	#[ test ]
+	//
+	fn chunked_message_too_big()
+	{
+		let mut config = IpcPeerConfig::default();
+		config.max_frame_size = 256;
+
+		let mut codec  = codec( config );
+		let     stream = ConnID::new();
+		let mut buf    = BytesMut::new();
+
+		for _ in 0..4 { buf.extend_from_slice( &chunk( stream, 100, false ) ); }
+
+		match codec.decode( &mut buf )
+		{
+			Err( EkkeIoError::FrameTooBig( _, 256 ) ) => {}
+			other                                     => panic!( "expected FrameTooBig, got: {:?}", other ),
+		}
+	}
+
+
+	#[ test ]
+	//
+	fn too_many_chunked_messages()
+	{
+		let mut codec = codec( IpcPeerConfig::default() );
+		let mut buf   = BytesMut::new();
+
+		for _ in 0..MAX_CHUNKED + 1 { buf.extend_from_slice( &chunk( ConnID::new(), 10, false ) ); }
+
+		match codec.decode( &mut buf )
+		{
+			Err( EkkeIoError::MalformedFrame( _ ) ) => {}
+			other                                   => panic!( "expected MalformedFrame, got: {:?}", other ),
+		}
+	}
+}
@@ -9,14 +9,15 @@
 //! Your service actor must return a response from it's handler for the request.
 //
 
-use crate :: { import::*, ConnID, EkkeIoError } ;
+use crate :: { import::*, ConnID, EkkeIoError, PeerInfo, VersionRange } ;
+use serde_bytes :: { ByteBuf };
 
 
 /// Represents a message that goes over the wire. It always contains a string service name
 /// to allow dispatching in the receiving application. A connection ID allows connection tracking.
 ///
 ///
-#[ derive( Debug, Serialize, Deserialize, Message, MessageResponse )]
+#[ derive( Debug, Clone, Serialize, Deserialize, Message, MessageResponse )]
 //
 pub struct IpcMessage
 {
@@ -42,7 +43,37 @@ pub struct IpcMessage
 	//
 	/// cbor encoded Service Message
 	///
-	pub payload: Vec<u8>
+	pub payload: Vec<u8>,
+
+
+	#[ serde( default ) ]
+	//
+	/// Whether the payload is compressed on the wire. IpcPeer takes care of this, you will never
+	/// see a compressed payload.
+	///
+	pub compressed: bool,
+
+
+	#[ serde( default ) ]
+	//
+	/// On requests, the versions of the service the caller can work with. None takes the newest.
+	///
+	pub version: Option< VersionRange >,
+
+
+	#[ serde( skip ) ]
+	//
+	/// In what order IpcPeer writes outgoing messages, see [`Priority`](enum.Priority.html). This is only
+	/// used locally, it's not sent over the wire.
+	///
+	pub priority: Priority,
+
+
+	#[ serde( skip ) ]
+	//
+	/// The connection this message came in on. Set by IpcPeer on incoming messages, never sent over the wire.
+	///
+	pub peer: Option< PeerInfo >,
 }
 
 
@@ -65,9 +96,39 @@ impl IpcMessage
 			  service
 			, ms_type
 			, conn_id
-			, payload: serde_cbor::to_vec( &payload ).unwrap()
+			, payload   : serde_cbor::to_vec( &payload ).unwrap()
+			, compressed: false
+			, version   : None
+			, priority  : Priority::Normal
+			, peer      : None
 		}
 	}
+
+
+	/// Ask for a version of the service within range.
+	///
+	///     IpcMessage::new( "RegisterApplication".into(), request, MessageType::IpcRequestOut, conn_id )
+	///
+	///     	.with_version( VersionRange::at_least( 2 ) )
+	///
+	pub fn with_version( mut self, range: VersionRange ) -> Self
+	{
+		self.version = Some( range );
+		self
+	}
+
+
+	/// Set the priority for writing this message, eg. for a response carrying a big file:
+	///
+	///     IpcMessage::new( msg.service, file, MessageType::Response, msg.conn_id )
+	///
+	///     	.with_priority( Priority::Bulk )
+	///
+	pub fn with_priority( mut self, priority: Priority ) -> Self
+	{
+		self.priority = priority;
+		self
+	}
 }
 
 
@@ -85,6 +146,7 @@ impl IpcMessage
 ///     	IpcRequestOut
 ///     	{
 ///     		ipc_peer: ekke_server.recipient(),
+///     		resolve : None,
 ///
 ///     		ipc_msg: IpcMessage::new
 ///     		(
@@ -97,9 +159,17 @@ impl IpcMessage
 ///
 ///     )).unwraps( &log );
 ///
-#[ derive( Message ) ] #[ rtype( result="Result<IpcResponse, EkkeIoError>" ) ]
+/// Set resolve if a retry should go to another recipient than the first attempt, eg. because the connection
+/// might be replaced in between. It's asked before every retry, and when it returns None, ipc_peer is used again.
+///
+#[ derive( Clone, Message ) ] #[ rtype( result="Result<IpcResponse, EkkeIoError>" ) ]
 //
-pub struct IpcRequestOut{ pub ipc_peer: Recipient< IpcMessage >, pub ipc_msg: IpcMessage }
+pub struct IpcRequestOut{ pub ipc_peer: Recipient< IpcMessage >, pub ipc_msg: IpcMessage, pub resolve: Option< PeerResolver > }
+
+
+/// Finds the recipient to send a retry to, see [`IpcRequestOut`](struct.IpcRequestOut.html).
+///
+pub type PeerResolver = Arc< dyn Fn() -> Option< Recipient< IpcMessage > > + Send + Sync >;
 
 
 /// This is a wrapper type around IpcMessage to allow implementing handlers for a specific message type.
@@ -144,7 +214,7 @@ pub struct IpcRequestOut{ pub ipc_peer: Recipient< IpcMessage >, pub ipc_msg: Ip
 
 /// Helps flow decisions for messages of type IpcMessage
 ///
-#[ derive( Serialize, Deserialize, Debug ) ]
+#[ derive( Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq ) ]
 //
 pub enum MessageType
 {
@@ -155,4 +225,130 @@ pub enum MessageType
 	Ack           ,
 	Broadcast     ,
 	Error         ,
+
+	/// Heartbeat, IpcPeer answers it with a Pong carrying the same conn_id.
+	///
+	Ping          ,
+	Pong          ,
+
+	/// Last frame sent by an IpcPeer that shuts down cleanly, so the other side can tell
+	/// a clean close from a crash. When a peer gets rejected during the handshake, the payload
+	/// contains the reason.
+	///
+	Goodbye       ,
+
+	/// First frame on every connection, see [`Handshake`](struct.Handshake.html).
+	///
+	Handshake     ,
+
+	/// Several messages in one frame, the payload is a [`Batch`](struct.Batch.html). Only send these
+	/// to peers that agreed on the "batch" feature.
+	///
+	Batch         ,
+
+	/// A piece of a bigger message, the payload is a [`Chunk`](struct.Chunk.html). Only send these
+	/// to peers that agreed on the "chunks" feature.
+	///
+	Chunk         ,
+}
+
+
+
+/// Decides in what order IpcPeer writes the messages waiting in it's queue. Messages with a higher
+/// priority go first, messages with the same priority are written in the order they were queued.
+///
+/// Payloads of Normal and Bulk messages that are bigger than `IpcPeerConfig::chunk_size` are sent in
+/// chunks, so a big transfer doesn't hold up the messages with a higher priority that are queued after it.
+/// Pings, pongs and handshakes are always written as High.
+///
+#[ derive( Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash ) ]
+//
+pub enum Priority
+{
+	/// Control messages that should never wait for other traffic. They are never chunked.
+	///
+	High,
+
+	/// The default.
+	///
+	Normal,
+
+	/// Big transfers that can wait for everything else.
+	///
+	Bulk,
+}
+
+
+impl Default for Priority
+{
+	fn default() -> Self { Priority::Normal }
+}
+
+
+
+/// The payload of a message of type `MessageType::Batch`. The receiving IpcPeer handles the messages
+/// as if they came in one by one, so requests are handled concurrently.
+///
+#[ derive( Debug, Clone, Serialize, Deserialize ) ]
+//
+pub struct Batch
+{
+	pub messages: Vec< IpcMessage >,
+
+	/// Send the responses to the requests in this batch back in one batch, with the conn_id of
+	/// the batch, once they are all done. Otherwise they are sent as soon as they are ready.
+	///
+	pub batch_responses: bool,
+}
+
+
+
+/// The payload of a message of type `MessageType::Chunk`. The data of all the chunks with the same conn_id
+/// makes up a whole IpcMessage, cbor encoded. The receiving IpcPeer puts it back together and handles it
+/// like any other message.
+///
+#[ derive( Debug, Clone, Serialize, Deserialize ) ]
+//
+pub struct Chunk
+{
+	pub data: ByteBuf,
+
+	/// Whether this is the final chunk of the message.
+	///
+	pub last: bool,
+}
+
+
+
+/// Send several requests to a peer in one frame. You get the results in the same order as the requests.
+/// The conn_id of every request must be unique, like for IpcRequestOut.
+///
+///     let results = await!( rpc.send( IpcBatchOut
+///     {
+///     	ipc_peer       : ekke_server.recipient(),
+///     	requests       : vec![ first, second, third ],
+///     	batch_responses: true,
+///
+///     }))?;
+///
+#[ derive( Message ) ] #[ rtype( result="Vec< Result<IpcResponse, EkkeIoError> >" ) ]
+//
+pub struct IpcBatchOut
+{
+	pub ipc_peer       : Recipient< IpcMessage >,
+	pub requests       : Vec< IpcMessage >      ,
+	pub batch_responses: bool                   ,
+}
+
+
+
+/// Ask IpcPeer or Rpc to shut down cleanly. New incoming requests will be refused, responses to requests
+/// that are already being handled get until the deadline to come in. After that whatever is still outstanding
+/// is failed and the actor stops. IpcPeer will flush it's queued writes and say goodbye to the remote peer.
+///
+#[ derive( Debug, Clone, Copy, Message ) ]
+//
+pub struct Shutdown
+{
+	pub deadline: Duration,
 }
@@ -1,6 +1,21 @@
 use crate :: { import::* };
 
-use crate::{ MessageType, IpcMessage, IpcRequestIn, IpcResponse, IpcError, Rpc };
+use crate::{ MessageType, IpcMessage, Batch, IpcRequestIn, IpcResponse, IpcError, Rpc, ConnID, Shutdown, EkkeIoError, PeerInfo, RemoteError, ErrorCode, ipc_codec::IpcCodec, peer_info::ReadCredentials, rpc::PeerClosed };
+
+
+pub(crate) mod config   ;
+pub(crate) mod handshake;
+pub(crate) mod batch    ;
+pub(crate) mod scheduler;
+pub(crate) mod queue    ;
+
+pub use config   ::IpcPeerConfig;
+pub use handshake::{ Handshake, Agreement, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION };
+
+use batch    ::BatchCollector;
+use scheduler::Scheduler     ;
+use queue    ::{ WriteQueue, Outgoing };
+
 
 /// Hides the underlying socket handling from client. The constructor takes a unix stream,
 /// but later will probably take any stream type. It also takes a Recipient<IpcRequestIn>
@@ -9,8 +24,25 @@ use crate::{ MessageType, IpcMessage, IpcRequestIn, IpcResponse, IpcError, Rpc }
 /// Will forward any IpcMessage you send to it on the network stream serialized as cbor,
 /// and will send every incoming message to your rpc.
 ///
-/// Currently uses Rc on a field because actix normally keeps an actor in the same thread.
-/// This might change later to make it Send and Sync.
+/// When configured with a heartbeat (see [`IpcPeerConfig`](struct.IpcPeerConfig.html)), IpcPeer will ping the
+/// remote peer regularly and close the connection when it stays silent for longer than the idle timeout.
+/// Outstanding requests sent over this peer are failed when the connection goes away.
+///
+/// The first thing IpcPeer does on a new connection is send a [`Handshake`](struct.Handshake.html). Nothing
+/// the remote peer sends is processed before it's handshake has come in and both sides agree on a protocol
+/// version and codec. Incompatible peers get a goodbye with the reason and are disconnected.
+///
+/// Send it a [`Shutdown`](struct.Shutdown.html) to close the connection cleanly.
+///
+/// Outgoing messages go through a bounded queue (see `IpcPeerConfig::queue_capacity`) and are written by a single
+/// writer task, by [`Priority`](enum.Priority.html) and in the order they were queued within a priority. Big payloads
+/// are sent in chunks, so urgent messages don't have to wait for a bulk transfer to finish. The capacity counts every
+/// message that hasn't been written completely yet. When the queue is full, an IpcMessage sent to this actor is
+/// dropped with an error. Use [`QueueIpcMessage`](struct.QueueIpcMessage.html) if you want to wait for room
+/// or get the error back.
+///
+/// The state shared with the tasks of the connection is Send and Sync, so IpcPeers can run on any arbiter
+/// and share one Rpc.
 ///
 #[ derive( Debug ) ]  #[allow(clippy::type_complexity)]
 //
@@ -19,12 +51,49 @@ pub struct IpcPeer<S>
 	where S: AsyncRead + AsyncWrite
 
 {
-	  sink: Rc<RefCell< SplitSink<Framed<S, Codec<IpcMessage, IpcMessage>>> >>
-	, log : Logger
+	  queue    : WriteQueue
+	, log      : Logger
+	, rpc      : Addr<Rpc>
+	, config   : IpcPeerConfig
+	, listener : AbortHandle
+	, last_seen: Arc<Mutex< Instant >>
+	, pending  : Arc<Mutex< HashMap<ConnID, String> >>
+	, in_flight: Arc<Mutex< HashSet<ConnID> >>
+	, closing  : Arc<AtomicBool>
+	, ping     : Option<( ConnID, Instant )>
+	, rtt      : Option< Duration >
+	, goodbye  : bool
+	, reason   : &'static str
+	, agreement: Option< Agreement >
+	, compress : Arc<AtomicBool>
+	, chunking : Arc<AtomicBool>
+	, peer_id  : ConnID
 }
 
+
 impl<S> Actor for IpcPeer<S> where S: AsyncRead + AsyncWrite + 'static
-{ type Context = Context<Self>; }
+{
+	type Context = Context<Self>;
+
+
+	fn started( &mut self, ctx: &mut Self::Context )
+	{
+		if let Some( interval ) = self.config.heartbeat_interval.or( self.config.idle_timeout )
+		{
+			ctx.run_interval( interval, |peer, ctx| peer.heartbeat( ctx ) );
+		}
+	}
+
+
+	fn stopped( &mut self, ctx: &mut Self::Context )
+	{
+		self.listener.abort();
+
+		self.fail_pending( self.reason, ctx );
+
+		self.rpc.do_send( PeerClosed( self.peer_id ) );
+	}
+}
 
 
 impl<S> IpcPeer<S>
@@ -34,23 +103,97 @@ impl<S> IpcPeer<S>
 {
 	pub fn new( connection: S, rpc: Addr<Rpc>, addr: Addr<Self>, log: Logger ) -> Self
 	{
-		let codec: Codec<IpcMessage, IpcMessage>  = Codec::new().packed( true );
+		Self::with_config( connection, rpc, addr, log, IpcPeerConfig::default() )
+	}
+
+
+	/// Like new, but lets you configure heartbeat and timeouts.
+	///
+	pub fn with_config( connection: S, rpc: Addr<Rpc>, addr: Addr<Self>, log: Logger, config: IpcPeerConfig ) -> Self
+	{
+		let mut ours = Handshake::ours();
+
+		if !config.compression { ours.compression.clear(); }
+
+		// Whatever the socket tells us beats what we were told.
+		//
+		let credentials = connection.peer_credentials().or( config.credentials );
+		let peer_id     = ConnID::new();
+
+		let compress = Arc::new( AtomicBool::new( false ) );
+		let codec    = IpcCodec::new( &config, &ours, compress.clone() );
 
 		let (sink, stream) = codec.framed( connection ).split();
 		let listen_log     = log.clone();
+		let write_log      = log.clone();
+		let write_addr     = addr.clone();
+		let last_seen      = Arc::new( Mutex::new( Instant::now() ) );
+		let pending        = Arc::new( Mutex::new( HashMap::new() ) );
+		let in_flight      = Arc::new( Mutex::new( HashSet::new() ) );
+		let closing        = Arc::new( AtomicBool::new( false ) );
+		let chunking       = Arc::new( AtomicBool::new( false ) );
+		let scheduler      = Scheduler::new( config.chunk_size, chunking.clone(), log.clone() );
+
+		let (listen, listener) = abortable( Self::listen
+		(
+			  stream
+			, rpc.clone()
+			, addr
+			, ours.clone()
+			, PeerInfo { id: peer_id, credentials }
+			, config.max_malformed_frames
+			, config.batch_timeout
+			, Shared { last_seen: last_seen.clone(), pending: pending.clone(), in_flight: in_flight.clone(), closing: closing.clone() }
+			, listen_log
+		));
+
+		Arbiter::spawn( async move
+		{
+			let _ = await!( listen );
+
+			Ok(())
+
+		}.boxed().compat());
+
+
+		let (mut queue, outgoing) = WriteQueue::new( config.queue_capacity );
+
+		// The queue is empty, so this can't fail for lack of room.
+		//
+		let _ = queue.push( IpcMessage::new( String::new(), ours, MessageType::Handshake, ConnID::new() ) );
 
 		Arbiter::spawn( async move
 		{
-			await!( Self::listen( stream, rpc, addr, listen_log ) );
+			await!( Self::write_queue( outgoing, sink, scheduler, write_addr.clone(), write_log ) );
+
+			// Either the queue got closed after the goodbye, or the connection failed.
+			//
+			write_addr.do_send( Disconnected );
 
 			Ok(())
 
 		}.boxed().compat());
 
+
 		Self
 		{
-			  sink: Rc::new( RefCell::new( sink ))
+			  queue
 			, log
+			, rpc
+			, config
+			, listener
+			, last_seen
+			, pending
+			, in_flight
+			, closing
+			, ping   : None
+			, rtt    : None
+			, goodbye  : false
+			, reason   : "Connection to peer lost"
+			, agreement: None
+			, compress
+			, chunking
+			, peer_id
 		}
 
 	}
@@ -60,64 +203,394 @@ impl<S> IpcPeer<S>
 	///
 	#[ inline ]
 	//
-	async fn listen( mut stream: SplitStream<Framed<S, Codec<IpcMessage, IpcMessage>>>, rpc: Addr<Rpc>, self_addr: Addr<Self>, log: Logger )
+	async fn listen
+	(
+		  mut stream   : SplitStream<Framed<S, IpcCodec>>
+		, rpc          : Addr<Rpc>
+		, self_addr    : Addr<Self>
+		, ours         : Handshake
+		, peer_info    : PeerInfo
+		, max_errors   : usize
+		, batch_timeout: Duration
+		, shared       : Shared
+		, log          : Logger
+	)
 	{
+		let mut malformed = 0;
+		let mut agreed    = false;
+
 		loop
 		{
 			let option: Option< Result< IpcMessage, _ > > = await!( stream.next() );
 
-			let frame = match option
+			let mut frame = match option
 			{
 				Some( connection ) =>
 				{
 					match connection
 					{
 						Ok ( frame ) => frame,
-						Err( error ) =>
+
+						// These we can skip, the codec has consumed the whole frame and only it's payload went over
+						// our limits. If it keeps happening the peer is broken or hostile, so we stop listening.
+						//
+						Err( error @ EkkeIoError::PayloadTooBig (..) ) |
+						Err( error @ EkkeIoError::NestingTooDeep(_ ) ) =>
 						{
-							error!( &log, "Error extracting IpcMessage from stream: {:#?}", error );
+							malformed += 1;
+
+							error!( &log, "Error extracting IpcMessage from stream: {}", error );
+
+							if malformed >= max_errors
+							{
+								error!( &log, "IpcPeer: received {} frames over our limits, closing connection.", malformed );
+
+								self_addr.do_send( Disconnected );
+								return;
+							}
+
 							continue;
 						}
+
+						// Cbor has no marker where the next frame starts, so after a malformed frame we lost track of
+						// where we are in the stream. Otherwise the connection is broken.
+						//
+						Err( error ) =>
+						{
+							error!( &log, "IpcPeer: closing connection: {}", error );
+
+							self_addr.do_send( Disconnected );
+							return;
+						}
 					}
 				},
 
-				None => return     // Disconnected
+				None =>  // Disconnected
+				{
+					self_addr.do_send( Disconnected );
+					return;
+				}
 			};
 
-			// Wrap ipc message, so that the correct handler can be called in Rpc
-			// We spawn the future immediately here to avoid blocking the loop which should start processing
-			// the next message.
-			//
-			let log_loop = log.clone();
-			let rpc      = rpc.clone();
-			let peer     = self_addr.clone().recipient();
+			*shared.last_seen.lock() = Instant::now();
+
+
+			if let MessageType::Goodbye = frame.ms_type
+			{
+				// A peer that rejects us tells us why.
+				//
+				let reason: Option<String> = Rpc::deserialize( frame.payload ).unwrap_or( None );
 
-			Arbiter::spawn( async move	{ match frame.ms_type
+				self_addr.do_send( GoodbyeReceived( reason ) );
+				return;
+			}
+
+
+			if !agreed
+			{
+				let result = match frame.ms_type
+				{
+					MessageType::Handshake => Rpc::deserialize::<Handshake>( frame.payload )
+
+						.map_err ( |e| EkkeIoError::IncompatiblePeer( format!( "could not read handshake: {}", e ) ) )
+						.and_then( |theirs| ours.negotiate( &theirs ) ),
+
+					_ => Err( EkkeIoError::IncompatiblePeer( format!( "expected a handshake, got: {:?}", frame.ms_type ) ) ),
+				};
+
+				let failed = result.is_err();
+
+				self_addr.do_send( HandshakeDone( result ) );
+
+				if failed { return; }
+
+				agreed = true;
+				continue;
+			}
+
+
+			if let MessageType::Batch = frame.ms_type
+			{
+				Self::unpack( frame, &rpc, &self_addr, &peer_info, batch_timeout, &shared, &log );
+				continue;
+			}
+
+			Self::dispatch( frame, self_addr.clone().recipient(), &rpc, &self_addr, &peer_info, &shared, &log );
+		}
+	}
+
+
+
+	/// Dispatch the messages in a batch as if they came in one by one. If the peer asked for it, the responses
+	/// are collected and sent back in a single batch. Only requests, responses and errors can be batched,
+	/// anything else is dropped.
+	///
+	fn unpack
+	(
+		  frame        : IpcMessage
+		, rpc          : &Addr<Rpc>
+		, self_addr    : &Addr<Self>
+		, peer_info    : &PeerInfo
+		, batch_timeout: Duration
+		, shared       : &Shared
+		, log          : &Logger
+	)
+	{
+		let batch: Batch = match Rpc::deserialize( frame.payload )
+		{
+			Ok ( batch ) => batch,
+			Err( error ) =>
+			{
+				error!( log, "IpcPeer: could not read batch: {}", error );
+				return;
+			}
+		};
+
+		let requests = batch.messages.iter().filter( |m| m.ms_type == MessageType::IpcRequestIn ).count();
+
+		let reply = if batch.batch_responses && requests > 0
+		{
+			BatchCollector::new( frame.conn_id, requests, batch_timeout, self_addr.clone().recipient() ).start().recipient()
+		}
+
+		else { self_addr.clone().recipient() };
+
+
+		for msg in batch.messages
+		{
+			match msg.ms_type
 			{
-				MessageType::IpcRequestIn =>
+				MessageType::IpcRequestIn |
+				MessageType::Response     |
+				MessageType::Error        => Self::dispatch( msg, reply.clone(), rpc, self_addr, peer_info, shared, log ),
+
+				_ => warn!( log, "IpcPeer: ignoring {:?} in batch", msg.ms_type ),
+			}
+		}
+	}
+
 
-					awaits!( rpc.send( IpcRequestIn{ ipc_msg: frame, ipc_peer: peer } ) ).unwraps( &log_loop ),
 
-				MessageType::Response =>
+	/// Handle one incoming message. Responses to requests go to reply.
+	///
+	fn dispatch
+	(
+		  mut frame: IpcMessage
+		, reply    : Recipient< IpcMessage >
+		, rpc      : &Addr<Rpc>
+		, self_addr: &Addr<Self>
+		, peer_info: &PeerInfo
+		, shared   : &Shared
+		, log      : &Logger
+	)
+	{
+		match frame.ms_type
+		{
+			// Answer pings straight away, they don't concern rpc.
+			//
+			MessageType::Ping =>
+			{
+				self_addr.do_send( IpcMessage::new( String::new(), (), MessageType::Pong, frame.conn_id ) );
+				return;
+			}
 
-					awaits!( rpc.send( IpcResponse   { ipc_msg: frame, ipc_peer: peer } ) ).unwraps( &log_loop ),
+			MessageType::Pong =>
+			{
+				self_addr.do_send( PongReceived( frame.conn_id ) );
+				return;
+			}
 
-				MessageType::Error =>
+			MessageType::Handshake =>
+			{
+				warn!( log, "IpcPeer: ignoring second handshake from peer" );
+				return;
+			}
 
-					awaits!( rpc.send( IpcError      { ipc_msg: frame, ipc_peer: peer } ) ).unwraps( &log_loop ),
+			MessageType::Response | MessageType::Error =>
+			{
+				shared.pending.lock().remove( &frame.conn_id );
+			}
 
-				_ =>
+			MessageType::IpcRequestIn =>
+			{
+				if shared.closing.load( Ordering::SeqCst )
 				{
-					error!( log_loop, "Unimplemented message type: {:?}", frame.ms_type );
+					let _ = reply.do_send( RemoteError::new( ErrorCode::Unavailable, frame.service, "Peer is shutting down" ).into_message( frame.conn_id ) );
+					return;
+				}
 
-					// If in debug mode, we crash here. In release just move on.
-					//
-					#[ cfg( debug_assertions ) ]
-					//
-					unimplemented!();
+				shared.in_flight.lock().insert( frame.conn_id );
+			}
+
+			_ => {}
+		}
+
+
+		frame.peer = Some( peer_info.clone() );
+
+
+		// Wrap ipc message, so that the correct handler can be called in Rpc
+		// We spawn the future immediately here to avoid blocking the loop which should start processing
+		// the next message.
+		//
+		let log_loop = log.clone();
+		let rpc      = rpc.clone();
+		let peer     = reply;
+
+		// Rpc stops once it's drained after a shutdown, while peers might still be sending us things.
+		//
+		Arbiter::spawn( async move	{ let gone = |e: MailboxError| debug!( log_loop, "IpcPeer: Rpc is gone, dropping incoming message: {}", e );
+
+		match frame.ms_type
+		{
+			MessageType::IpcRequestIn =>
+
+				awaits!( rpc.send( IpcRequestIn{ ipc_msg: frame, ipc_peer: peer } ) ).unwrap_or_else( gone ),
+
+			MessageType::Response =>
+
+				awaits!( rpc.send( IpcResponse   { ipc_msg: frame, ipc_peer: peer } ) ).unwrap_or_else( gone ),
+
+			MessageType::Error =>
+
+				awaits!( rpc.send( IpcError      { ipc_msg: frame, ipc_peer: peer } ) ).unwrap_or_else( gone ),
+
+			// A misbehaving peer shouldn't bring us down.
+			//
+			_ => error!( log_loop, "IpcPeer: ignoring message of unsupported type: {:?}", frame.ms_type ),
+
+		};	Ok(()) }.boxed().compat());
+	}
+
+
+
+	/// The only place that writes to the connection. Before every frame it takes everything that is waiting in
+	/// the queue and lets the scheduler pick what goes first. The queue only lets in as many messages as there is
+	/// room for until they are written, so that's bounded. Stops when the queue is closed and everything has been
+	/// written, or when writing fails.
+	///
+	async fn write_queue
+	(
+		  mut queue    : Outgoing
+		, mut sink     : SplitSink<Framed<S, IpcCodec>>
+		, mut scheduler: Scheduler
+		, self_addr    : Addr<Self>
+		, log          : Logger
+	)
+	{
+		let mut open = true;
+
+		loop
+		{
+			// Take everything, so a message with a higher priority never waits behind a full scheduler.
+			//
+			while open
+			{
+				match queue.receiver.try_next()
+				{
+					Ok ( Some( msg ) ) => scheduler.push( msg ),
+					Ok ( None        ) => open = false,
+					Err( _           ) => break, // nothing waiting
 				}
+			}
+
+			let ( frame, done ) = match scheduler.next()
+			{
+				Some( next ) => next,
+
+				None if open => match await!( queue.receiver.next() )
+				{
+					Some( msg ) => { scheduler.push( msg ); continue; }
+					None        => return,
+				},
+
+				None => return,
+			};
+
+			match awaits!( sink.send_async( frame ) )
+			{
+				Ok (_) => { trace!( log, "Ekke: successfully wrote to stream"       ); },
+				Err(e) => { error!( log, "Ekke: failed to write to stream: {:?}", e ); return; }
+			}
+
+			// Once the last frame of a message is out, it no longer counts against the capacity.
+			//
+			if done && queue.written()
+			{
+				self_addr.do_send( Room );
+			}
+		}
+	}
+
+
+
+	/// Queue a message for writing without waiting.
+	///
+	fn write( &mut self, msg: IpcMessage ) -> Result< (), EkkeIoError >
+	{
+		self.queue.push( msg )
+	}
+
+
+
+	/// Runs every heartbeat interval. Closes the connection if the peer has been silent for to long,
+	/// otherwise sends a new ping.
+	///
+	fn heartbeat( &mut self, ctx: &mut Context<Self> )
+	{
+		if let Some( timeout ) = self.config.idle_timeout
+		{
+			if self.last_seen.lock().elapsed() > timeout
+			{
+				warn!( self.log, "IpcPeer: nothing received from peer for {:?}, closing connection.", timeout );
+
+				ctx.stop();
+				return;
+			}
+		}
+
+		if self.config.heartbeat_interval.is_some()
+		{
+			let conn_id = ConnID::new();
+
+			self.ping = Some(( conn_id, Instant::now() ));
+
+			if let Err( e ) = self.write( IpcMessage::new( String::new(), (), MessageType::Ping, conn_id ) )
+			{
+				debug!( self.log, "IpcPeer: could not send ping: {}", e );
+			}
+		}
+	}
+
 
-			};	Ok(()) }.boxed().compat());
+
+	/// Queue the goodbye frame after everything queued before it and close the queue. The writer task
+	/// stops us once it has flushed everything.
+	///
+	fn say_goodbye( &mut self )
+	{
+		if self.goodbye { return; }
+
+		self.goodbye = true;
+
+		self.queue.push_last( IpcMessage::new( String::new(), None::<String>, MessageType::Goodbye, ConnID::new() ) );
+	}
+
+
+
+	/// Let rpc know that requests we sent out over this connection will never get a response.
+	///
+	fn fail_pending( &mut self, reason: &str, ctx: &mut Context<Self> )
+	{
+		for ( conn_id, service ) in self.pending.lock().drain()
+		{
+			debug!( self.log, "IpcPeer: failing outstanding request for service: {}", &service );
+
+			self.rpc.do_send( IpcError
+			{
+				ipc_peer: ctx.address().recipient(),
+				ipc_msg : RemoteError::new( ErrorCode::Unavailable, service, reason ).into_message( conn_id ),
+			});
 		}
 	}
 }
@@ -131,24 +604,901 @@ impl<S> Handler< IpcMessage > for IpcPeer<S>
 {
 	type Result = ();
 
-	fn handle( &mut self, msg: IpcMessage, _ctx: &mut Context<Self> ) -> Self::Result
+	fn handle( &mut self, msg: IpcMessage, ctx: &mut Context<Self> ) -> Self::Result
 	{
-		let sink = self.sink.clone();
-		let log  = self.log .clone();
+		// Whatever couldn't be sent has been logged and reported to rpc by now.
+		//
+		if let MessageType::Batch = msg.ms_type
+		{
+			let _ = self.send_batch( msg, ctx );
+			return;
+		}
 
-		Arbiter::spawn( async move
+		match self.outgoing( &msg )
+		{
+			Ok( drained ) =>
+			{
+				let _ = self.write_or_fail( msg, ctx );
+
+				if drained { self.say_goodbye(); }
+			}
+
+			Err( e ) => self.refuse( &msg, &e, ctx ),
+		}
+	}
+}
+
+
+
+impl<S> IpcPeer<S>
+
+	where S: AsyncRead + AsyncWrite + 'static
+
+{
+	/// Book keeping for a message on it's way out, whether it comes in as IpcMessage or QueueIpcMessage. Refuses
+	/// new requests once we are shutting down, remembers the requests we send so they can be failed when the connection
+	/// goes away and forgets the requests of the remote peer we answer. Returns whether this answers the last request
+	/// we still owed while shutting down, in which case the goodbye can follow it.
+	///
+	fn outgoing( &mut self, msg: &IpcMessage ) -> Result< bool, EkkeIoError >
+	{
+		let closing = self.closing.load( Ordering::SeqCst );
+
+		match msg.ms_type
 		{
-			let mut stay_alive = sink.borrow_mut();
+			MessageType::IpcRequestIn if closing => Err( EkkeIoError::ShuttingDown( "IpcPeer".to_string() ) ),
 
-			match awaits!( stay_alive.send_async( msg ) )
+			MessageType::IpcRequestIn =>
 			{
-				Ok (_) => { info! ( log, "Ekke: successfully wrote to stream"       ); },
-				Err(e) => { error!( log, "Ekke: failed to write to stream: {:?}", e ); }
+				self.pending.lock().insert( msg.conn_id, msg.service.clone() );
+				Ok( false )
 			}
 
-			Ok(())
+			MessageType::Response | MessageType::Error =>
+			{
+				let answered = self.in_flight.lock().remove( &msg.conn_id );
 
-		}.boxed().compat());
+				Ok( answered && closing && self.in_flight.lock().is_empty() )
+			}
+
+			_ => Ok( false ),
+		}
+	}
+
+
+	/// Tell rpc that a request won't be sent.
+	///
+	fn refuse( &mut self, msg: &IpcMessage, error: &EkkeIoError, ctx: &mut Context<Self> )
+	{
+		self.rpc.do_send( IpcError
+		{
+			ipc_peer: ctx.address().recipient(),
+			ipc_msg : RemoteError::from_error( msg.service.clone(), error ).into_message( msg.conn_id ),
+		});
 	}
 
+
+	/// Do the book keeping for the messages in a batch we send out, like for individual messages. If the peer
+	/// doesn't know about batches, the messages are sent one by one. Requests that can't be sent are reported to rpc,
+	/// the error returned is the last one that happened.
+	///
+	fn send_batch( &mut self, mut msg: IpcMessage, ctx: &mut Context<Self> ) -> Result< (), EkkeIoError >
+	{
+		let mut batch: Batch = match Rpc::deserialize( msg.payload.clone() )
+		{
+			Ok ( batch ) => batch,
+			Err( error ) =>
+			{
+				error!( self.log, "IpcPeer: not sending invalid batch: {}", error );
+				return Err( error );
+			}
+		};
+
+		let mut drained = false;
+		let mut refused = None ;
+
+		batch.messages.retain( |inner| match self.outgoing( inner )
+		{
+			Ok( last ) =>
+			{
+				drained |= last;
+				true
+			}
+
+			Err( e ) =>
+			{
+				self.refuse( inner, &e, ctx );
+				refused = Some( e );
+				false
+			}
+		});
+
+		let mut result = refused.map_or( Ok(()), Err );
+
+		if batch.messages.is_empty() { return result; }
+
+		let batching = self.agreement.as_ref().map( |a| a.features.iter().any( |f| f == "batch" ) ).unwrap_or( false );
+
+		if !batching
+		{
+			for inner in batch.messages
+			{
+				if let Err( e ) = self.write_or_fail( inner, ctx ) { result = Err( e ); }
+			}
+		}
+
+		else
+		{
+			let requests: Vec<ConnID> = batch.messages.iter()
+
+				.filter( |inner| inner.ms_type == MessageType::IpcRequestIn )
+				.map   ( |inner| inner.conn_id                              )
+				.collect()
+			;
+
+			if result.is_err()
+			{
+				msg = IpcMessage::new( msg.service, &batch, MessageType::Batch, msg.conn_id ).with_priority( msg.priority );
+			}
+
+			if let Err( e ) = self.write( msg )
+			{
+				error!( self.log, "IpcPeer: dropping outgoing batch: {}", e );
+
+				for conn_id in requests
+				{
+					self.fail_request( conn_id, &e, ctx );
+				}
+
+				result = Err( e );
+			}
+		}
+
+		if drained { self.say_goodbye(); }
+
+		result
+	}
+
+
+	/// Queue a message, and if that fails, make sure a request doesn't wait forever for a response.
+	///
+	fn write_or_fail( &mut self, msg: IpcMessage, ctx: &mut Context<Self> ) -> Result< (), EkkeIoError >
+	{
+		let service = msg.service.clone();
+		let conn_id = msg.conn_id;
+
+		self.write( msg ).map_err( |e|
+		{
+			error!( self.log, "IpcPeer: dropping outgoing message for service {}: {}", &service, e );
+
+			self.fail_request( conn_id, &e, ctx );
+
+			e
+		})
+	}
+
+
+	/// Tell rpc that an outgoing request couldn't be sent, if it was one.
+	///
+	fn fail_request( &mut self, conn_id: ConnID, error: &EkkeIoError, ctx: &mut Context<Self> )
+	{
+		if let Some( service ) = self.pending.lock().remove( &conn_id )
+		{
+			self.rpc.do_send( IpcError
+			{
+				ipc_peer: ctx.address().recipient(),
+				ipc_msg : RemoteError::from_error( service, error ).into_message( conn_id ),
+			});
+		}
+	}
+}
+
+
+
+/// What to do when the write queue of an IpcPeer is full.
+///
+#[ derive( Debug, Clone, Copy, PartialEq, Eq ) ]
+//
+pub enum WhenFull
+{
+	/// Wait until there is room in the queue.
+	///
+	Wait,
+
+	/// Return `EkkeIoError::QueueFull` immediately.
+	///
+	Fail,
+}
+
+
+
+/// Queue a message for writing and get told whether that worked. With `WhenFull::Wait` the returned future
+/// resolves once the message is in the queue, which lets a fast producer slow down to the pace of the connection.
+///
+/// Messages that wait for room are queued in the order they started waiting, but they can be overtaken by messages
+/// sent to IpcPeer in the meantime. If you need strict ordering, wait for each send before doing the next.
+///
+/// Requests are refused with `EkkeIoError::ShuttingDown` once the IpcPeer is shutting down. A batch is queued whole
+/// or not at all, without waiting for room.
+///
+#[ derive( Debug, Message ) ] #[ rtype( result="Result<(), EkkeIoError>" ) ]
+//
+pub struct QueueIpcMessage
+{
+	pub ipc_msg  : IpcMessage,
+	pub when_full: WhenFull  ,
+}
+
+
+impl<S> Handler< QueueIpcMessage > for IpcPeer<S>
+
+	where S: AsyncRead + AsyncWrite + 'static
+
+{
+	type Result = ActixFuture< Result<(), EkkeIoError> >;
+
+	fn handle( &mut self, msg: QueueIpcMessage, ctx: &mut Context<Self> ) -> Self::Result
+	{
+		let QueueIpcMessage { ipc_msg, when_full } = msg;
+
+		if let MessageType::Batch = ipc_msg.ms_type
+		{
+			let result = self.send_batch( ipc_msg, ctx );
+
+			return ActixFuture::from( async move { result } );
+		}
+
+		let drained = match self.outgoing( &ipc_msg )
+		{
+			Ok ( drained ) => drained,
+			Err( e       ) => return ActixFuture::from( async move { Err( e ) } ),
+		};
+
+		let conn_id = ipc_msg.conn_id;
+		let pending = self.pending.clone();
+
+		let result = match when_full
+		{
+			WhenFull::Fail =>
+			{
+				let result = self.write( ipc_msg );
+
+				if result.is_err() { pending.lock().remove( &conn_id ); }
+
+				ActixFuture::from( async move { result } )
+			}
+
+			WhenFull::Wait =>
+			{
+				let queued = self.queue.push_wait( ipc_msg );
+
+				ActixFuture::from( async move
+				{
+					// The sender is dropped when the IpcPeer stops.
+					//
+					let result = await!( queued ).unwrap_or( Err( EkkeIoError::ConnectionClosed ) );
+
+					if result.is_err() { pending.lock().remove( &conn_id ); }
+
+					result
+				})
+			}
+		};
+
+		// The goodbye waits for parked messages, so this one still goes out before it.
+		//
+		if drained { self.say_goodbye(); }
+
+		result
+	}
+}
+
+
+
+impl<S> Handler< Shutdown > for IpcPeer<S>
+
+	where S: AsyncRead + AsyncWrite + 'static
+
+{
+	type Result = ();
+
+	fn handle( &mut self, msg: Shutdown, ctx: &mut Context<Self> ) -> Self::Result
+	{
+		if self.closing.load( Ordering::SeqCst ) { return; }
+
+		info!( self.log, "IpcPeer: shutting down, {} requests in flight", self.in_flight.lock().len() );
+
+		self.closing.store( true, Ordering::SeqCst );
+		self.reason = "Connection closed by shutdown";
+
+		if self.in_flight.lock().is_empty()
+		{
+			self.say_goodbye();
+		}
+
+		else
+		{
+			ctx.run_later( msg.deadline, |peer, ctx|
+			{
+				warn!( peer.log, "IpcPeer: shutdown deadline reached with {} requests in flight", peer.in_flight.lock().len() );
+
+				peer.say_goodbye();
+			});
+		}
+	}
+}
+
+
+/// Ask an IpcPeer for the last measured round trip time. This will be None until the first
+/// pong has been received, so you need to configure a heartbeat in order to get a value.
+///
+#[ derive( Debug, Message ) ] #[ rtype( result="Option<Duration>" ) ]
+//
+pub struct GetRoundTrip;
+
+
+impl<S> Handler< GetRoundTrip > for IpcPeer<S>
+
+	where S: AsyncRead + AsyncWrite + 'static
+
+{
+	type Result = Option<Duration>;
+
+	fn handle( &mut self, _msg: GetRoundTrip, _ctx: &mut Context<Self> ) -> Self::Result
+	{
+		self.rtt
+	}
+}
+
+
+
+/// Sent by the listen task when a pong comes in.
+///
+#[ derive( Message ) ] struct PongReceived( ConnID );
+
+
+impl<S> Handler< PongReceived > for IpcPeer<S>
+
+	where S: AsyncRead + AsyncWrite + 'static
+
+{
+	type Result = ();
+
+	fn handle( &mut self, msg: PongReceived, _ctx: &mut Context<Self> ) -> Self::Result
+	{
+		match self.ping
+		{
+			Some(( conn_id, sent )) if conn_id == msg.0 =>
+			{
+				self.rtt  = Some( sent.elapsed() );
+				self.ping = None;
+
+				trace!( self.log, "IpcPeer: round trip time: {:?}", self.rtt );
+			}
+
+			// A pong for a ping we no longer wait for, just ignore it.
+			//
+			_ => {}
+		}
+	}
+}
+
+
+
+/// Sent by the listen task when the peer said goodbye, possibly with the reason it rejected us.
+///
+#[ derive( Message ) ] struct GoodbyeReceived( Option<String> );
+
+
+impl<S> Handler< GoodbyeReceived > for IpcPeer<S>
+
+	where S: AsyncRead + AsyncWrite + 'static
+
+{
+	type Result = ();
+
+	fn handle( &mut self, msg: GoodbyeReceived, ctx: &mut Context<Self> ) -> Self::Result
+	{
+		match msg.0
+		{
+			Some( reason ) =>
+			{
+				error!( self.log, "IpcPeer: peer refused the connection: {}", reason );
+
+				self.reason = "Peer refused the connection";
+			}
+
+			None =>
+			{
+				info!( self.log, "IpcPeer: peer closed the connection cleanly" );
+
+				self.reason = "Peer closed the connection";
+			}
+		}
+
+		ctx.stop();
+	}
+}
+
+
+
+/// Sent by the listen task when the handshake of the peer came in.
+///
+#[ derive( Message ) ] struct HandshakeDone( Result<Agreement, EkkeIoError> );
+
+
+impl<S> Handler< HandshakeDone > for IpcPeer<S>
+
+	where S: AsyncRead + AsyncWrite + 'static
+
+{
+	type Result = ();
+
+	fn handle( &mut self, msg: HandshakeDone, _ctx: &mut Context<Self> ) -> Self::Result
+	{
+		match msg.0
+		{
+			Ok( agreement ) =>
+			{
+				debug!( self.log, "IpcPeer: handshake done: {:?}", &agreement );
+
+				self.compress.store( agreement.compression.as_ref().map( |c| c == "zstd" ).unwrap_or( false ), Ordering::SeqCst );
+				self.chunking.store( agreement.features.iter().any( |f| f == "chunks" )                     , Ordering::SeqCst );
+				self.agreement = Some( agreement );
+			}
+
+			// Tell the peer why and let the writer task stop us once that's flushed.
+			//
+			Err( error ) =>
+			{
+				error!( self.log, "IpcPeer: rejecting peer: {}", error );
+
+				let goodbye = IpcMessage::new( String::new(), Some( error.to_string() ), MessageType::Goodbye, ConnID::new() );
+
+				self.queue.push_last( goodbye );
+
+				self.goodbye = true;
+				self.reason  = "Handshake with peer failed";
+			}
+		}
+	}
+}
+
+
+
+/// Sent by the writer task when it made room in the write queue while senders are waiting for it.
+///
+#[ derive( Message ) ] struct Room;
+
+
+impl<S> Handler< Room > for IpcPeer<S>
+
+	where S: AsyncRead + AsyncWrite + 'static
+
+{
+	type Result = ();
+
+	fn handle( &mut self, _msg: Room, _ctx: &mut Context<Self> ) -> Self::Result
+	{
+		self.queue.room();
+	}
+}
+
+
+
+/// Ask an IpcPeer what it agreed on with the remote peer during the handshake. None while
+/// the handshake hasn't completed.
+///
+#[ derive( Debug, Message ) ] #[ rtype( result="Option<Agreement>" ) ]
+//
+pub struct GetAgreement;
+
+
+impl<S> Handler< GetAgreement > for IpcPeer<S>
+
+	where S: AsyncRead + AsyncWrite + 'static
+
+{
+	type Result = Option<Agreement>;
+
+	fn handle( &mut self, _msg: GetAgreement, _ctx: &mut Context<Self> ) -> Self::Result
+	{
+		self.agreement.clone()
+	}
+}
+
+
+
+/// State the listen task shares with the actor.
+///
+struct Shared
+{
+	last_seen: Arc<Mutex< Instant >>,
+	pending  : Arc<Mutex< HashMap<ConnID, String> >>,
+	in_flight: Arc<Mutex< HashSet<ConnID> >>,
+	closing  : Arc<AtomicBool>,
+}
+
+
+
+/// Sent by the listen task when the peer closes the connection.
+///
+#[ derive( Message ) ] struct Disconnected;
+
+
+impl<S> Handler< Disconnected > for IpcPeer<S>
+
+	where S: AsyncRead + AsyncWrite + 'static
+
+{
+	type Result = ();
+
+	fn handle( &mut self, _msg: Disconnected, ctx: &mut Context<Self> ) -> Self::Result
+	{
+		debug!( self.log, "IpcPeer: peer closed the connection" );
+
+		ctx.stop();
+	}
+}
+
+
+
+#[ cfg( test ) ]
+//
+mod tests
+{
+	use super::*;
+
+	use actix          :: { System                     };
+	use bytes          :: { BytesMut                   };
+	use std            :: { io                         };
+	use tokio::prelude :: { task, Async, Poll          };
+	use tokio::timer   :: { Delay, Timeout             };
+
+
+	/// What one end of a pipe reads.
+	///
+	#[ derive( Default ) ]
+	//
+	struct Buffer
+	{
+		data  : VecDeque<u8>,
+		closed: bool,
+		reader: Option< task::Task >,
+	}
+
+
+	/// One end of an in memory connection. Writes never block, reads wait until the other end writes
+	/// or goes away.
+	///
+	struct End
+	{
+		rx: Arc<Mutex< Buffer >>,
+		tx: Arc<Mutex< Buffer >>,
+	}
+
+
+	fn pipe() -> ( End, End )
+	{
+		let a = Arc::new( Mutex::new( Buffer::default() ) );
+		let b = Arc::new( Mutex::new( Buffer::default() ) );
+
+		( End { rx: a.clone(), tx: b.clone() }, End { rx: b, tx: a } )
+	}
+
+
+	impl End
+	{
+		fn close( &self )
+		{
+			let mut tx = self.tx.lock();
+
+			tx.closed = true;
+
+			if let Some( reader ) = tx.reader.take() { reader.notify(); }
+		}
+	}
+
+
+	impl io::Read for End
+	{
+		fn read( &mut self, buf: &mut [u8] ) -> io::Result<usize>
+		{
+			let mut rx = self.rx.lock();
+
+			if rx.data.is_empty()
+			{
+				if rx.closed { return Ok( 0 ); }
+
+				rx.reader = Some( task::current() );
+
+				return Err( io::ErrorKind::WouldBlock.into() );
+			}
+
+			let n = buf.len().min( rx.data.len() );
+
+			for ( to, from ) in buf.iter_mut().zip( rx.data.drain( ..n ) ) { *to = from; }
+
+			Ok( n )
+		}
+	}
+
+
+	impl io::Write for End
+	{
+		fn write( &mut self, buf: &[u8] ) -> io::Result<usize>
+		{
+			let mut tx = self.tx.lock();
+
+			if tx.closed { return Err( io::ErrorKind::BrokenPipe.into() ); }
+
+			tx.data.extend( buf );
+
+			if let Some( reader ) = tx.reader.take() { reader.notify(); }
+
+			Ok( buf.len() )
+		}
+
+
+		fn flush( &mut self ) -> io::Result<()> { Ok(()) }
+	}
+
+
+	impl AsyncRead  for End {}
+
+	impl AsyncWrite for End
+	{
+		fn shutdown( &mut self ) -> Poll<(), io::Error>
+		{
+			self.close();
+
+			Ok( Async::Ready(()) )
+		}
+	}
+
+
+	impl Drop for End
+	{
+		fn drop( &mut self ) { self.close(); }
+	}
+
+
+
+	fn log() -> Logger
+	{
+		Logger::root( slog::Discard, o!() )
+	}
+
+
+	fn peer( end: End, config: IpcPeerConfig ) -> Addr< IpcPeer<End> >
+	{
+		let rpc = Rpc::default().start();
+
+		IpcPeer::create( |ctx| IpcPeer::with_config( end, rpc, ctx.address(), log(), config ) )
+	}
+
+
+	/// Run a test on an actix system. It fails if it takes more than 10 seconds.
+	///
+	fn run( test: impl StdFuture< Output = () > + Send + 'static )
+	{
+		System::run( move ||
+		{
+			let test = async move { await!( test ); Ok::<(), ()>(()) }.boxed().compat();
+
+			Arbiter::spawn( Timeout::new( test, Duration::from_secs( 10 ) ).then( |result|
+			{
+				assert!( result.is_ok(), "test timed out" );
+
+				System::current().stop();
+
+				Ok(())
+			}));
+		});
+	}
+
+
+	async fn sleep( duration: Duration )
+	{
+		let _ = awaits!( Delay::new( Instant::now() + duration ) );
+	}
+
+
+	/// Everything that comes out of the other end, until the IpcPeer closes the connection.
+	///
+	async fn received( mut end: End ) -> Vec< IpcMessage >
+	{
+		let mut data = BytesMut::new();
+		let mut buf  = [ 0u8; 4096 ];
+
+		loop
+		{
+			match io::Read::read( &mut end, &mut buf )
+			{
+				Ok ( 0 ) => break,
+				Ok ( n ) => data.extend_from_slice( &buf[ ..n ] ),
+				Err( _ ) => await!( sleep( Duration::from_millis( 5 ) ) ),
+			}
+		}
+
+		let mut codec  = IpcCodec::new( &IpcPeerConfig::default(), &Handshake::ours(), Arc::new( AtomicBool::new( false ) ) );
+		let mut frames = Vec::new();
+
+		while let Some( frame ) = codec.decode( &mut data ).unwrap()
+		{
+			frames.push( frame );
+		}
+
+		frames
+	}
+
+
+	fn numbered( i: usize ) -> IpcMessage
+	{
+		IpcMessage::new( "Numbered".to_string(), i, MessageType::Response, ConnID::new() )
+	}
+
+
+	// Every message that has been written gives it's place in the queue back, so a peer can send many more
+	// messages than fit in the queue, and a sender that waits for room always gets it.
+	//
+	#[ test ]
+	//
+	fn writes_more_than_capacity()
+	{
+		const CAPACITY: usize = 4 ;
+		const SENT    : usize = 50;
+
+		run( async
+		{
+			let ( ours, theirs ) = pipe();
+
+			let mut config = IpcPeerConfig::default();
+			config.queue_capacity = CAPACITY;
+
+			let peer = peer( ours, config );
+
+			for i in 0..SENT
+			{
+				let queued = awaits!( peer.send( QueueIpcMessage { ipc_msg: numbered( i ), when_full: WhenFull::Wait } ) );
+
+				assert!( queued.unwrap().is_ok() );
+			}
+
+			peer.do_send( Shutdown { deadline: Duration::from_secs( 1 ) } );
+
+			let numbers: Vec<usize> = await!( received( theirs ) ).iter()
+
+				.filter( |frame| frame.service == "Numbered" )
+				.map   ( |frame| des( &frame.payload ).unwrap() )
+				.collect()
+			;
+
+			assert_eq!( numbers, ( 0..SENT ).collect::< Vec<usize> >() );
+		});
+	}
+
+
+	// The remote peer answers our pings, which gives us a round trip time.
+	//
+	#[ test ]
+	//
+	fn ping_is_answered()
+	{
+		run( async
+		{
+			let ( ours, theirs ) = pipe();
+
+			let mut config = IpcPeerConfig::default();
+			config.heartbeat_interval = Some( Duration::from_millis( 20 ) );
+
+			let pinging  = peer( ours  , config                    );
+			let _remote  = peer( theirs, IpcPeerConfig::default() );
+
+			await!( sleep( Duration::from_millis( 300 ) ) );
+
+			let rtt = awaits!( pinging.send( GetRoundTrip ) ).unwrap();
+
+			assert!( rtt.is_some() );
+			assert!( rtt.unwrap() < Duration::from_millis( 300 ) );
+		});
+	}
+
+
+	// Without pings or pongs, there's no round trip time to report.
+	//
+	#[ test ]
+	//
+	fn no_round_trip_without_heartbeat()
+	{
+		run( async
+		{
+			let ( ours, theirs ) = pipe();
+
+			let silent  = peer( ours  , IpcPeerConfig::default() );
+			let _remote = peer( theirs, IpcPeerConfig::default() );
+
+			await!( sleep( Duration::from_millis( 100 ) ) );
+
+			assert!( awaits!( silent.send( GetRoundTrip ) ).unwrap().is_none() );
+		});
+	}
+
+
+	// A peer that doesn't say anything for longer than the idle timeout gets disconnected.
+	//
+	#[ test ]
+	//
+	fn idle_peer_is_closed()
+	{
+		run( async
+		{
+			let ( ours, theirs ) = pipe();
+
+			let mut config = IpcPeerConfig::default();
+			config.idle_timeout = Some( Duration::from_millis( 50 ) );
+
+			let peer = peer( ours, config );
+
+			// We never write to theirs, so all that comes out before the connection is closed is our handshake
+			// and possibly pings.
+			//
+			let frames = await!( received( theirs ) );
+
+			assert_eq!( frames[0].ms_type, MessageType::Handshake );
+
+			match awaits!( peer.send( GetRoundTrip ) )
+			{
+				Err( MailboxError::Closed ) => {}
+				_                           => panic!( "expected the IpcPeer to be stopped" ),
+			}
+		});
+	}
+
+
+	// Shutdown writes what was queued, then says goodbye and closes the connection. New requests are refused
+	// in the meantime.
+	//
+	#[ test ]
+	//
+	fn shutdown_drains_and_says_goodbye()
+	{
+		run( async
+		{
+			let ( ours, theirs ) = pipe();
+
+			let peer = peer( ours, IpcPeerConfig::default() );
+
+			for i in 0..3
+			{
+				peer.do_send( numbered( i ) );
+			}
+
+			peer.do_send( Shutdown { deadline: Duration::from_secs( 1 ) } );
+
+			let request = IpcMessage::new( "Numbered".to_string(), 3, MessageType::IpcRequestIn, ConnID::new() );
+			let refused = awaits!( peer.send( QueueIpcMessage { ipc_msg: request, when_full: WhenFull::Wait } ) ).unwrap();
+
+			match refused
+			{
+				Err( EkkeIoError::ShuttingDown(_) ) => {}
+				_                                   => panic!( "expected ShuttingDown" ),
+			}
+
+			// Returns once the connection is closed.
+			//
+			let frames = await!( received( theirs ) );
+
+			let numbers: Vec<usize> = frames.iter()
+
+				.filter( |frame| frame.service == "Numbered" )
+				.map   ( |frame| des( &frame.payload ).unwrap() )
+				.collect()
+			;
+
+			assert_eq!( numbers, vec![ 0, 1, 2 ] );
+			assert_eq!( frames.first().unwrap().ms_type, MessageType::Handshake );
+			assert_eq!( frames.last ().unwrap().ms_type, MessageType::Goodbye   );
+		});
+	}
 }
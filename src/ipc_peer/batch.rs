@@ -0,0 +1,89 @@
+use crate :: { import::*, IpcMessage, MessageType, Batch, ConnID };
+
+
+/// Collects the responses to the requests in an incoming batch, and sends them to the IpcPeer as a
+/// single batch once they are all there. If that takes longer than the timeout, what we have is sent as a batch
+/// and the rest goes out one by one as it comes in. Stops once the IpcPeer is gone.
+///
+pub(crate) struct BatchCollector
+{
+	conn_id  : ConnID                 ,
+	expected : usize                  ,
+	timeout  : Duration               ,
+	responses: Vec< IpcMessage >      ,
+	ipc_peer : Recipient< IpcMessage >,
+
+	/// Set once the timeout sent what we had, responses coming in later are sent on their own.
+	///
+	flushed  : bool                   ,
+}
+
+
+impl BatchCollector
+{
+	pub(crate) fn new( conn_id: ConnID, expected: usize, timeout: Duration, ipc_peer: Recipient< IpcMessage > ) -> Self
+	{
+		Self { conn_id, expected, timeout, responses: Vec::with_capacity( expected ), ipc_peer, flushed: false }
+	}
+
+
+	/// Send what we have as a batch.
+	///
+	fn flush( &mut self, ctx: &mut Context<Self> )
+	{
+		self.expected = self.expected.saturating_sub( self.responses.len() );
+		self.flushed  = true;
+
+		if self.responses.is_empty() { return; }
+
+		let batch = Batch { messages: std::mem::replace( &mut self.responses, Vec::new() ), batch_responses: false };
+
+		self.send( IpcMessage::new( String::new(), batch, MessageType::Batch, self.conn_id ), ctx );
+	}
+
+
+	fn send( &mut self, msg: IpcMessage, ctx: &mut Context<Self> )
+	{
+		// The connection is gone, nobody is waiting for the rest.
+		//
+		if self.ipc_peer.do_send( msg ).is_err() { ctx.stop(); }
+	}
+}
+
+
+impl Actor for BatchCollector
+{
+	type Context = Context<Self>;
+
+	fn started( &mut self, ctx: &mut Self::Context )
+	{
+		ctx.run_later( self.timeout, |collector, ctx| collector.flush( ctx ) );
+	}
+}
+
+
+
+impl Handler< IpcMessage > for BatchCollector
+{
+	type Result = ();
+
+	fn handle( &mut self, msg: IpcMessage, ctx: &mut Context<Self> ) -> Self::Result
+	{
+		if self.flushed
+		{
+			self.expected = self.expected.saturating_sub( 1 );
+			self.send( msg, ctx );
+		}
+
+		else
+		{
+			self.responses.push( msg );
+
+			if self.responses.len() < self.expected { return; }
+
+			self.flush( ctx );
+		}
+
+		if self.expected == 0 { ctx.stop(); }
+	}
+}
@@ -0,0 +1,99 @@
+use crate :: { import::*, PeerCredentials };
+
+
+/// Tunables for an [`IpcPeer`](struct.IpcPeer.html). The defaults keep the behaviour of a plain connection,
+/// so you only pay for what you turn on.
+///
+#[ derive( Debug, Clone ) ]
+//
+pub struct IpcPeerConfig
+{
+	/// Send a ping to the remote peer at this interval. The pong is used to measure the round trip time.
+	/// Default: None (no pings are sent).
+	///
+	pub heartbeat_interval: Option< Duration >,
+
+	/// Close the connection if nothing was received from the peer for this long. Outstanding requests
+	/// will be failed. This is checked every heartbeat_interval, or every idle_timeout if no heartbeat
+	/// is configured. Make sure it's comfortably bigger than the heartbeat interval of the remote peer.
+	/// Default: None (a silent peer is kept around forever).
+	///
+	pub idle_timeout: Option< Duration >,
+
+	/// How many outgoing messages can wait to be written before senders get `EkkeIoError::QueueFull`
+	/// or have to wait. A message counts until it's last chunk is written. Default: 64.
+	///
+	pub queue_capacity: usize,
+
+	/// The biggest frame we accept from the peer, in bytes. A bigger frame closes the connection, since
+	/// we can't find the start of the next frame. Default: 16MiB.
+	///
+	pub max_frame_size: usize,
+
+	/// The biggest payload we accept, in bytes. Frames with a bigger payload are dropped. Default: 16MiB.
+	///
+	pub max_payload_size: usize,
+
+	/// How deep containers in the payload can be nested. Default: 64.
+	///
+	pub max_nesting: usize,
+
+	/// Close the connection after this many frames had a payload over the size or nesting limits. A frame
+	/// that can't be decoded at all always closes the connection. Default: 3.
+	///
+	pub max_malformed_frames: usize,
+
+	/// Who is on the other end of the connection. IpcPeer reads the credentials from the socket itself when it can,
+	/// this is only used for transports where it can't, eg. a stream that wraps a socket you checked yourself with
+	/// [`PeerCredentials::of`](struct.PeerCredentials.html#method.of). Default: None.
+	///
+	pub credentials: Option< PeerCredentials >,
+
+	/// Offer payload compression to the peer. Only has effect with the `compression` feature, and only
+	/// if the peer supports it too. Default: true.
+	///
+	pub compression: bool,
+
+	/// Payloads smaller than this many bytes are sent uncompressed, since it's not worth the effort.
+	/// Default: 1024.
+	///
+	pub compression_threshold: usize,
+
+	/// Payloads of outgoing messages with Normal or Bulk [`Priority`](enum.Priority.html) that are bigger than
+	/// this many bytes are sent in chunks of this size, so messages with a higher priority can be written in
+	/// between. Only if the peer supports it. Default: 64KiB.
+	///
+	pub chunk_size: usize,
+
+	/// How long we wait for the responses to a batch of requests before sending what we have. Responses that come
+	/// in later are sent one by one. Default: 30s.
+	///
+	pub batch_timeout: Duration,
+}
+
+
+impl Default for IpcPeerConfig
+{
+	fn default() -> Self
+	{
+		Self
+		{
+			heartbeat_interval: None,
+			idle_timeout      : None,
+			queue_capacity    : 64  ,
+
+			max_frame_size      : 16 * 1024 * 1024,
+			max_payload_size    : 16 * 1024 * 1024,
+			max_nesting         : 64              ,
+			max_malformed_frames: 3               ,
+
+			credentials: None,
+
+			compression          : true,
+			compression_threshold: 1024,
+
+			chunk_size   : 64 * 1024                ,
+			batch_timeout: Duration::from_secs( 30 ),
+		}
+	}
+}
@@ -0,0 +1,214 @@
+use crate :: { import::*, EkkeIoError };
+
+
+/// The version of the wire protocol spoken by this version of ekke_io. Bump it whenever the encoding of
+/// IpcMessage or MessageType changes.
+///
+/// - 1: the first version with a handshake
+/// - 2: `IpcMessage::compressed`
+/// - 3: `IpcMessage::version`
+/// - 4: `MessageType::Batch`
+/// - 5: `MessageType::Chunk`
+///
+pub const PROTOCOL_VERSION    : u32 = 5;
+
+/// The oldest protocol version we can still talk to. Nothing behaves differently depending on the agreed version,
+/// so we only talk to peers that can handle everything we might send them.
+///
+pub const MIN_PROTOCOL_VERSION: u32 = 5;
+
+
+// Everything we know about, in order of preference. Both sides pick the first entry the other side
+// also supports, so they always agree without another round trip.
+//
+const CODECS     : &[&str] = &[ "cbor"                                    ];
+const FEATURES   : &[&str] = &[ "heartbeat", "goodbye", "batch", "chunks" ];
+
+#[ cfg(      feature = "compression"   ) ] const COMPRESSION: &[&str] = &[ "zstd" ];
+#[ cfg( not( feature = "compression" ) ) ] const COMPRESSION: &[&str] = &[        ];
+
+
+/// The first frame each IpcPeer sends on a new connection. Nothing else is processed before the
+/// handshake of the remote peer has been received and accepted.
+///
+#[ derive( Debug, Clone, PartialEq, Eq, Serialize, Deserialize ) ]
+//
+pub struct Handshake
+{
+	pub protocol_version    : u32        ,
+	pub min_protocol_version: u32        ,
+	pub codecs              : Vec<String>,
+	pub compression         : Vec<String>,
+	pub features            : Vec<String>,
+}
+
+
+impl Handshake
+{
+	/// What this version of ekke_io has to offer. IpcPeer leaves out compression if you turned
+	/// it off in IpcPeerConfig.
+	///
+	pub fn ours() -> Self
+	{
+		let owned = |list: &[&str]| list.iter().map( |s| s.to_string() ).collect();
+
+		Self
+		{
+			protocol_version    : PROTOCOL_VERSION     ,
+			min_protocol_version: MIN_PROTOCOL_VERSION ,
+			codecs              : owned( CODECS      ) ,
+			compression         : owned( COMPRESSION ) ,
+			features            : owned( FEATURES    ) ,
+		}
+	}
+
+
+	/// Work out what we will use with a peer that sent `theirs`. Fails if we have no protocol version
+	/// or codec in common.
+	///
+	pub fn negotiate( &self, theirs: &Handshake ) -> Result< Agreement, EkkeIoError >
+	{
+		let version = self.protocol_version.min( theirs.protocol_version );
+
+		if version < self.min_protocol_version || version < theirs.min_protocol_version
+		{
+			return Err( EkkeIoError::IncompatiblePeer( format!
+			(
+				"we speak protocol versions {}-{}, the peer speaks {}-{}",
+				self  .min_protocol_version, self  .protocol_version,
+				theirs.min_protocol_version, theirs.protocol_version,
+			)));
+		}
+
+		let common = |preference: &[&str], ours: &[String], theirs: &[String]| -> Vec<String>
+		{
+			preference.iter()
+
+				.filter( |p| ours.iter().any( |o| o == *p ) && theirs.iter().any( |t| t == *p ) )
+				.map   ( |p| p.to_string() )
+				.collect()
+		};
+
+		let codec = common( CODECS, &self.codecs, &theirs.codecs ).into_iter().next().ok_or_else( ||
+		{
+			EkkeIoError::IncompatiblePeer( format!( "no common codec, peer supports: {:?}", theirs.codecs ) )
+		})?;
+
+		Ok( Agreement
+		{
+			protocol_version: version,
+			codec           ,
+			compression     : common( COMPRESSION, &self.compression, &theirs.compression ).into_iter().next(),
+			features        : common( FEATURES   , &self.features   , &theirs.features    ),
+		})
+	}
+}
+
+
+
+/// What both sides of a connection agreed on during the handshake.
+///
+#[ derive( Debug, Clone, PartialEq, Eq ) ]
+//
+pub struct Agreement
+{
+	pub protocol_version: u32           ,
+	pub codec           : String        ,
+	pub compression     : Option<String>,
+	pub features        : Vec<String>   ,
+}
+
+
+
+#[ cfg( test ) ]
+//
+mod tests
+{
+	use super::*;
+
+
+	#[ test ]
+	//
+	fn agrees_with_itself()
+	{
+		let ours      = Handshake::ours();
+		let agreement = ours.negotiate( &ours ).unwrap();
+
+		assert_eq!( agreement.protocol_version, PROTOCOL_VERSION );
+		assert_eq!( agreement.codec           , "cbor"           );
+		assert_eq!( agreement.features        , ours.features    );
+	}
+
+
+	#[ test ]
+	//
+	fn speaks_the_older_version()
+	{
+		let mut theirs = Handshake::ours();
+
+		theirs.protocol_version     = PROTOCOL_VERSION + 1;
+		theirs.min_protocol_version = MIN_PROTOCOL_VERSION;
+
+		assert_eq!( Handshake::ours().negotiate( &theirs ).unwrap().protocol_version, PROTOCOL_VERSION );
+	}
+
+
+	#[ test ]
+	//
+	fn refuses_versions_before_the_minimum()
+	{
+		let mut theirs = Handshake::ours();
+
+		theirs.protocol_version     = MIN_PROTOCOL_VERSION - 1;
+		theirs.min_protocol_version = 1;
+
+		match Handshake::ours().negotiate( &theirs )
+		{
+			Err( EkkeIoError::IncompatiblePeer( _ ) ) => {}
+			other                                     => panic!( "expected IncompatiblePeer, got: {:?}", other ),
+		}
+	}
+
+
+	#[ test ]
+	//
+	fn refuses_incompatible_versions()
+	{
+		let mut theirs = Handshake::ours();
+
+		theirs.protocol_version     = PROTOCOL_VERSION + 2;
+		theirs.min_protocol_version = PROTOCOL_VERSION + 1;
+
+		match Handshake::ours().negotiate( &theirs )
+		{
+			Err( EkkeIoError::IncompatiblePeer( _ ) ) => {}
+			other                                     => panic!( "expected IncompatiblePeer, got: {:?}", other ),
+		}
+	}
+
+
+	#[ test ]
+	//
+	fn refuses_without_common_codec()
+	{
+		let mut theirs = Handshake::ours();
+		theirs.codecs  = vec![ "json".to_string() ];
+
+		assert!( Handshake::ours().negotiate( &theirs ).is_err() );
+	}
+
+
+	#[ test ]
+	//
+	fn only_common_features()
+	{
+		let mut theirs   = Handshake::ours();
+		theirs.features  = vec![ "goodbye".to_string(), "teleport".to_string() ];
+		theirs.compression.clear();
+
+		let agreement = Handshake::ours().negotiate( &theirs ).unwrap();
+
+		assert_eq!( agreement.features   , vec![ "goodbye".to_string() ] );
+		assert_eq!( agreement.compression, None                         );
+	}
+}
@@ -0,0 +1,301 @@
+use crate :: { import::*, IpcMessage, EkkeIoError };
+
+
+/// The sending end of the write queue of an IpcPeer. It holds at most `capacity` messages that haven't been
+/// written yet. Senders that want to wait for room are parked in order and get their message queued as soon as
+/// the writer task makes room.
+///
+/// The channel itself is unbounded, the bound is kept by counting the messages that have been queued but not
+/// written yet. That way there is a single bound, however many clones of a sender would be around.
+///
+pub(crate) struct WriteQueue
+{
+	sender  : channel::mpsc::UnboundedSender< IpcMessage >,
+	queued  : Arc<AtomicUsize>                            ,
+	parked  : Arc<AtomicBool>                             ,
+	capacity: usize                                       ,
+	waiting : VecDeque<( IpcMessage, channel::oneshot::Sender< Result<(), EkkeIoError> > )>,
+
+	/// The last message, waiting for the parked senders to go first.
+	///
+	last    : Option< IpcMessage >                        ,
+}
+
+
+/// The end of the write queue the writer task takes messages from.
+///
+pub(crate) struct Outgoing
+{
+	pub(crate) receiver: channel::mpsc::UnboundedReceiver< IpcMessage >,
+	queued             : Arc<AtomicUsize>                              ,
+	parked             : Arc<AtomicBool>                               ,
+}
+
+
+impl Outgoing
+{
+	/// A message has been written completely. Returns whether there are senders waiting for room,
+	/// in which case the IpcPeer should call `WriteQueue::room`.
+	///
+	pub(crate) fn written( &self ) -> bool
+	{
+		self.queued.fetch_sub( 1, Ordering::SeqCst );
+
+		self.parked.load( Ordering::SeqCst )
+	}
+}
+
+
+impl WriteQueue
+{
+	/// The capacity is at least one, otherwise nothing could ever be written.
+	///
+	pub(crate) fn new( capacity: usize ) -> ( Self, Outgoing )
+	{
+		let (sender, receiver) = channel::mpsc::unbounded();
+
+		let queued = Arc::new( AtomicUsize::new( 0     ) );
+		let parked = Arc::new( AtomicBool ::new( false ) );
+
+		let queue = Self
+		{
+			sender                      ,
+			queued  : queued.clone()    ,
+			parked  : parked.clone()    ,
+			capacity: capacity.max( 1 ) ,
+			waiting : VecDeque::new()   ,
+			last    : None              ,
+		};
+
+		( queue, Outgoing { receiver, queued, parked } )
+	}
+
+
+	/// How many messages are queued and haven't been written yet, not counting the parked ones.
+	///
+	pub(crate) fn len( &self ) -> usize
+	{
+		self.queued.load( Ordering::SeqCst )
+	}
+
+
+	/// Queue a message, or fail with `QueueFull` if there is no room.
+	///
+	pub(crate) fn push( &mut self, msg: IpcMessage ) -> Result< (), EkkeIoError >
+	{
+		if self.last.is_some() { return Err( EkkeIoError::ConnectionClosed ); }
+
+		if self.len() >= self.capacity { return Err( EkkeIoError::QueueFull ); }
+
+		self.force( msg )
+	}
+
+
+	/// Queue a message as soon as there is room. The receiver resolves once it's queued.
+	///
+	pub(crate) fn push_wait( &mut self, msg: IpcMessage ) -> channel::oneshot::Receiver< Result<(), EkkeIoError> >
+	{
+		let (sender, receiver) = channel::oneshot::channel();
+
+		if self.last.is_some()
+		{
+			let _ = sender.send( Err( EkkeIoError::ConnectionClosed ) );
+
+			return receiver;
+		}
+
+		// Set this before looking for room, so the writer can't make room without telling us.
+		//
+		self.parked.store( true, Ordering::SeqCst );
+		self.waiting.push_back(( msg, sender ));
+
+		self.room();
+
+		receiver
+	}
+
+
+	/// Queue parked messages while there is room. Call this when `Outgoing::written` says someone is waiting.
+	///
+	pub(crate) fn room( &mut self )
+	{
+		while self.len() < self.capacity
+		{
+			let (msg, sender) = match self.waiting.pop_front()
+			{
+				Some( waiting ) => waiting,
+				None            => break  ,
+			};
+
+			let _ = sender.send( self.force( msg ) );
+		}
+
+		if !self.waiting.is_empty() { return; }
+
+		self.parked.store( false, Ordering::SeqCst );
+
+		if let Some( last ) = self.last.take()
+		{
+			let _ = self.force( last );
+
+			self.close();
+		}
+	}
+
+
+	/// Queue the last message and close the queue once the parked senders have had their turn. The last
+	/// message doesn't wait for room itself. New messages are refused from now on.
+	///
+	pub(crate) fn push_last( &mut self, msg: IpcMessage )
+	{
+		self.last = Some( msg );
+
+		self.room();
+	}
+
+
+	/// Close the queue. The writer task writes what was queued before and stops.
+	///
+	pub(crate) fn close( &mut self )
+	{
+		self.sender.close_channel();
+
+		for ( _, sender ) in self.waiting.drain( .. )
+		{
+			let _ = sender.send( Err( EkkeIoError::ConnectionClosed ) );
+		}
+
+		self.parked.store( false, Ordering::SeqCst );
+	}
+
+
+	fn force( &mut self, msg: IpcMessage ) -> Result< (), EkkeIoError >
+	{
+		// Count it first, the writer might have written it before we get to count it.
+		//
+		self.queued.fetch_add( 1, Ordering::SeqCst );
+
+		self.sender.unbounded_send( msg ).map_err( |_|
+		{
+			self.queued.fetch_sub( 1, Ordering::SeqCst );
+
+			EkkeIoError::ConnectionClosed
+		})
+	}
+}
+
+
+
+#[ cfg( test ) ]
+//
+mod tests
+{
+	use super::*;
+	use crate :: { MessageType, ConnID };
+
+
+	fn msg( i: usize ) -> IpcMessage
+	{
+		IpcMessage::new( String::new(), i, MessageType::Response, ConnID::new() )
+	}
+
+
+	// A parked sender gets it's message queued once the writer is done with one.
+	//
+	#[ test ]
+	//
+	fn written_makes_room()
+	{
+		let ( mut queue, mut outgoing ) = WriteQueue::new( 1 );
+
+		assert!( queue.push( msg( 0 ) ).is_ok() );
+
+		let mut receiver = queue.push_wait( msg( 1 ) );
+
+		assert!( receiver.try_recv().unwrap().is_none() );
+
+		outgoing.receiver.try_next().unwrap().unwrap();
+
+		assert!( outgoing.written() );
+
+		queue.room();
+
+		assert!( receiver.try_recv().unwrap().unwrap().is_ok() );
+		assert_eq!( queue.len(), 1 );
+	}
+
+
+	#[ test ]
+	//
+	fn push_fails_when_full()
+	{
+		let ( mut queue, _outgoing ) = WriteQueue::new( 2 );
+
+		assert!( queue.push( msg( 0 ) ).is_ok() );
+		assert!( queue.push( msg( 1 ) ).is_ok() );
+
+		match queue.push( msg( 2 ) )
+		{
+			Err( EkkeIoError::QueueFull ) => {}
+			_                             => panic!( "expected QueueFull" ),
+		}
+	}
+
+
+	#[ test ]
+	//
+	fn close_fails_waiting_senders()
+	{
+		let ( mut queue, _outgoing ) = WriteQueue::new( 1 );
+
+		assert!( queue.push( msg( 0 ) ).is_ok() );
+
+		let mut receiver = queue.push_wait( msg( 1 ) );
+
+		queue.close();
+
+		match receiver.try_recv()
+		{
+			Ok( Some( Err( EkkeIoError::ConnectionClosed ) ) ) => {}
+			_                                                 => panic!( "expected ConnectionClosed" ),
+		}
+	}
+
+
+	// The last message goes out after the parked ones, and nothing gets in after it.
+	//
+	#[ test ]
+	//
+	fn last_waits_for_parked_senders()
+	{
+		let ( mut queue, mut outgoing ) = WriteQueue::new( 1 );
+
+		assert!( queue.push( msg( 0 ) ).is_ok() );
+
+		let mut parked = queue.push_wait( msg( 1 ) );
+
+		queue.push_last( msg( 2 ) );
+
+		assert!( queue.push( msg( 3 ) ).is_err() );
+
+		let mut late = queue.push_wait( msg( 4 ) );
+
+		assert!( late.try_recv().unwrap().unwrap().is_err() );
+
+		let mut written = Vec::new();
+
+		while let Some( msg ) = outgoing.receiver.try_next().ok().and_then( |msg| msg )
+		{
+			written.push( des::<usize>( &msg.payload ).unwrap() );
+
+			if outgoing.written() { queue.room(); }
+		}
+
+		assert!( parked.try_recv().unwrap().unwrap().is_ok() );
+		assert_eq!( written, vec![ 0, 1, 2 ] );
+
+		// The queue is closed after the last message.
+		//
+		assert!( outgoing.receiver.try_next().unwrap().is_none() );
+	}
+}
@@ -0,0 +1,246 @@
+//! Decides in what order the writer task of an IpcPeer writes queued messages to the connection.
+//
+use crate :: { import::*, IpcMessage, MessageType, Priority, Chunk, ConnID };
+
+use serde_bytes :: { ByteBuf };
+
+
+/// Keeps a lane per `Priority` and always writes the next frame of the most
+/// urgent lane. Big messages are cut in chunks when they are pushed, so between two chunks a message with
+/// a higher priority can go first. Within a lane messages keep their order and the chunks of one message are
+/// never mixed with those of another.
+///
+pub(crate) struct Scheduler
+{
+	/// The frames of every queued message, per priority.
+	///
+	lanes: [ VecDeque< VecDeque< IpcMessage > >; 3 ],
+
+	chunk_size: usize,
+
+	/// Set once the peer agreed on the "chunks" feature.
+	///
+	chunking: Arc<AtomicBool>,
+
+	/// The goodbye is always the last frame we write.
+	///
+	goodbye: Option< IpcMessage >,
+
+	log: Logger,
+}
+
+
+impl Scheduler
+{
+	pub(crate) fn new( chunk_size: usize, chunking: Arc<AtomicBool>, log: Logger ) -> Self
+	{
+		Self
+		{
+			lanes  : [ VecDeque::new(), VecDeque::new(), VecDeque::new() ],
+			chunk_size                                                   ,
+			chunking                                                     ,
+			goodbye: None                                                ,
+			log                                                          ,
+		}
+	}
+
+
+	/// How many messages are waiting to be written, however many frames they take.
+	///
+	pub(crate) fn len( &self ) -> usize
+	{
+		self.lanes.iter().map( |lane| lane.len() ).sum::<usize>() + self.goodbye.iter().count()
+	}
+
+
+	pub(crate) fn push( &mut self, msg: IpcMessage )
+	{
+		let priority = match msg.ms_type
+		{
+			MessageType::Goodbye =>
+			{
+				self.goodbye = Some( msg );
+				return;
+			}
+
+			MessageType::Ping | MessageType::Pong | MessageType::Handshake => Priority::High,
+
+			_ => msg.priority,
+		};
+
+		let chunk = priority != Priority::High && self.chunk_size > 0 && msg.payload.len() > self.chunk_size;
+
+		let frames = if chunk && self.chunking.load( Ordering::SeqCst )
+		{
+			self.chunks( msg )
+		}
+
+		else { VecDeque::from( vec![ msg ] ) };
+
+		self.lanes[ priority as usize ].push_back( frames );
+	}
+
+
+	/// The next frame to write, if any, and whether it's the last frame of it's message.
+	///
+	pub(crate) fn next( &mut self ) -> Option<( IpcMessage, bool )>
+	{
+		for lane in self.lanes.iter_mut()
+		{
+			let ( frame, done ) = match lane.front_mut()
+			{
+				Some( frames ) => ( frames.pop_front(), frames.is_empty() ),
+				None           => continue,
+			};
+
+			if done { lane.pop_front(); }
+
+			if let Some( frame ) = frame { return Some(( frame, done )); }
+		}
+
+		self.goodbye.take().map( |goodbye| ( goodbye, true ) )
+	}
+
+
+	/// Cut a message in chunks. They share a new conn_id, so the peer can tell them apart from the chunks
+	/// of other messages.
+	///
+	fn chunks( &self, msg: IpcMessage ) -> VecDeque< IpcMessage >
+	{
+		let data = match serde_cbor::to_vec( &msg )
+		{
+			Ok ( data  ) => data,
+			Err( error ) =>
+			{
+				warn!( self.log, "IpcPeer: could not chunk message for service {}, sending it whole: {}", &msg.service, error );
+
+				return VecDeque::from( vec![ msg ] );
+			}
+		};
+
+		let stream = ConnID::new();
+		let count  = ( data.len() + self.chunk_size - 1 ) / self.chunk_size;
+
+		data.chunks( self.chunk_size ).enumerate().map( |( i, piece )|
+		{
+			let chunk = Chunk { data: ByteBuf::from( piece.to_vec() ), last: i + 1 == count };
+
+			IpcMessage::new( msg.service.clone(), chunk, MessageType::Chunk, stream )
+
+		}).collect()
+	}
+}
+
+
+
+#[ cfg( test ) ]
+//
+mod tests
+{
+	use super::*;
+	use crate :: { IpcPeerConfig, Handshake, ipc_codec::IpcCodec };
+
+	use bytes :: { BytesMut };
+
+
+	fn scheduler( chunk_size: usize ) -> Scheduler
+	{
+		Scheduler::new( chunk_size, Arc::new( AtomicBool::new( true ) ), Logger::root( slog::Discard, o!() ) )
+	}
+
+
+	/// The type of the next frame and whether it finishes it's message.
+	///
+	fn next( scheduler: &mut Scheduler ) -> Option<( MessageType, bool )>
+	{
+		scheduler.next().map( |( frame, done )| ( frame.ms_type, done ) )
+	}
+
+
+	fn bulk( size: usize ) -> IpcMessage
+	{
+		IpcMessage::new( "Upload".to_string(), ByteBuf::from( vec![ 7u8; size ] ), MessageType::Response, ConnID::new() )
+
+			.with_priority( Priority::Bulk )
+	}
+
+
+	#[ test ]
+	//
+	fn small_messages_are_sent_whole()
+	{
+		let mut scheduler = scheduler( 1024 );
+
+		scheduler.push( bulk( 100 ) );
+
+		assert_eq!( next( &mut scheduler ), Some(( MessageType::Response, true )) );
+		assert_eq!( next( &mut scheduler ), None                                );
+	}
+
+
+	// A ping pushed while a bulk message is half way goes out with the next frame, and the bulk message only
+	// stops counting once it's last chunk is out.
+	//
+	#[ test ]
+	//
+	fn high_overtakes_a_partly_written_bulk_message()
+	{
+		let mut scheduler = scheduler( 64 );
+
+		scheduler.push( bulk( 1000 ) );
+
+		assert_eq!( next( &mut scheduler ), Some(( MessageType::Chunk, false )) );
+		assert_eq!( scheduler.len(), 1 );
+
+		scheduler.push( IpcMessage::new( String::new(), (), MessageType::Ping, ConnID::new() ) );
+
+		assert_eq!( next( &mut scheduler ), Some(( MessageType::Ping , true  )) );
+		assert_eq!( next( &mut scheduler ), Some(( MessageType::Chunk, false )) );
+
+		let mut done = false;
+
+		while let Some(( ms_type, last )) = next( &mut scheduler )
+		{
+			assert_eq!( ms_type, MessageType::Chunk );
+			assert!   ( !done );
+
+			done = last;
+		}
+
+		assert!   ( done );
+		assert_eq!( scheduler.len(), 0 );
+	}
+
+
+	// Cut in chunks on one side and put back together by the codec on the other.
+	//
+	#[ test ]
+	//
+	fn chunks_round_trip()
+	{
+		let mut scheduler = scheduler( 64 );
+		let mut codec     = IpcCodec::new( &IpcPeerConfig::default(), &Handshake::ours(), Arc::new( AtomicBool::new( false ) ) );
+		let mut buf       = BytesMut::new();
+		let msg           = bulk( 1000 );
+
+		scheduler.push( msg.clone() );
+
+		let mut frames = 0;
+
+		while let Some(( frame, _ )) = scheduler.next()
+		{
+			codec.encode( frame, &mut buf ).unwrap();
+			frames += 1;
+		}
+
+		assert!( frames > 1 );
+
+		let whole = codec.decode( &mut buf ).unwrap().unwrap();
+
+		assert_eq!( whole.ms_type , msg.ms_type  );
+		assert_eq!( whole.conn_id , msg.conn_id  );
+		assert_eq!( whole.service , msg.service  );
+		assert_eq!( whole.payload , msg.payload  );
+		assert!   ( buf.is_empty() );
+	}
+}
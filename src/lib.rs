@@ -11,15 +11,20 @@
 //! - Ipc functionality (currently over unix domain sockets, but should become cross platform)
 //!   It could abstract out over all possible mechanisms, as stdin/stdout, ...
 //! - Http Server for frontends (websockets)
+//! - Encrypted and authenticated sessions with the noise protocol (feature `noise`)
+//! - Json schemas for the messages of your services (feature `schema`)
 //
 #![ forbid( unsafe_code ) ]
 #![ feature( await_macro, async_await, futures_api, arbitrary_self_types, specialization, nll, never_type, unboxed_closures ) ]
 
 mod conn_id;
+mod peer_info;
+mod remote_error;
 mod rpc;
 mod errors;
 mod ipc_peer;
 mod ipc_message;
+mod ipc_codec;
 mod log;
 
 
@@ -29,6 +34,14 @@ pub use conn_id::
 };
 
 
+pub use peer_info::
+{
+	  PeerInfo
+	, PeerCredentials
+	, Caller
+};
+
+
 pub use errors::
 {
 	  EkkeResult
@@ -36,9 +49,25 @@ pub use errors::
 };
 
 
+pub use remote_error::
+{
+	  RemoteError
+	, ErrorCode
+};
+
+
 pub use ipc_peer::
 {
 	  IpcPeer
+	, IpcPeerConfig
+	, GetRoundTrip
+	, QueueIpcMessage
+	, WhenFull
+	, Handshake
+	, Agreement
+	, GetAgreement
+	, PROTOCOL_VERSION
+	, MIN_PROTOCOL_VERSION
 };
 
 
@@ -53,6 +82,12 @@ pub use ipc_message::
 	PleaseAck      ,
 	IpcRequestIn ,
 	IpcRequestOut    ,
+	PeerResolver     ,
+	Shutdown       ,
+	Batch          ,
+	IpcBatchOut    ,
+	Chunk          ,
+	Priority       ,
 };
 
 pub use log::
@@ -67,6 +102,35 @@ pub use rpc::
 	  Rpc
 	, register_service::RegisterService
 	, register_service::RegisterServiceMethod
+	, register_service::UnregisterService
+	, Policy
+	, RequestContext
+	, Identity
+	, GrantRole
+	, RevokeRole
+	, RateLimit
+	, RpcStats
+	, GetRpcStats
+	, ConcurrencyLimit
+	, Interceptor
+	, Balance
+	, ServiceInfo
+	, LIST_SERVICES
+	, VersionRange
+	, Candidate
+	, ConfigureRpc
+	, RetryPolicy
+};
+
+
+#[ cfg( feature = "schema" ) ]
+//
+pub use rpc::
+{
+	  DescribeService
+	, ExportSchema
+	, Schema
+	, LIST_SCHEMAS
 };
 
 
@@ -82,6 +146,22 @@ pub use http_server::
 	HttpServer     ,
 	ResponseFuture ,
 	Responder      ,
+	WsStream       ,
+};
+
+
+#[ cfg( feature = "noise" ) ]
+//
+mod noise;
+
+
+#[ cfg( feature = "noise" ) ]
+//
+pub use noise::
+{
+	NoiseConfig ,
+	NoiseStream ,
+	Keypair     ,
 };
 
 
@@ -94,15 +174,17 @@ mod import
 	{
 
 		actix             :: { Actor, Addr, Arbiter, AsyncContext, Context, Handler, MailboxError,
-			                        Message, MessageResponse, Recipient, Supervised, SystemService, dev::ToEnvelope           },
+			                        Message, MessageResponse, Recipient, Request, Supervised, SystemService, dev::ToEnvelope  },
 		actix_async_await :: { ResponseStdFuture as ActixFuture                                             },
 
 		failure           :: { Fail, Error, format_err, ResultExt as _                                      },
 
-		futures           :: { channel, future::{ join_all, ok }                                            },
+		futures           :: { channel, future::{ join_all, ok, abortable, AbortHandle },
+		                       sink::SinkExt as _, stream::StreamExt as _                                   },
 		futures_util      :: { future::{ FutureExt }, try_future::TryFutureExt                              },
 
-		hashbrown         :: { HashMap                                                                      },
+		hashbrown         :: { HashMap, HashSet                                                             },
+		std::collections  :: { VecDeque                                                                     },
 		rand              :: { Rng                                                                          },
 
 		serde             :: { Serialize, Deserialize, de::DeserializeOwned                                 },
@@ -111,13 +193,16 @@ mod import
 		slog              :: { Drain, Logger, trace, debug, info, warn, error, crit, o                      },
 		slog_unwraps      :: { ResultExt as _                                                               },
 
-		std               :: { any::{ Any, TypeId }, cell::RefCell, convert::From, convert::TryFrom,
+		parking_lot       :: { Mutex                                                                        },
+
+		std               :: { any::{ Any, TypeId }, convert::From, convert::TryFrom,
 		                       env, fmt, future::Future as StdFuture, net::SocketAddr, path::PathBuf,
-		                       process::Command, rc::Rc, sync::Arc, pin::Pin                                },
+		                       process::Command, sync::Arc, pin::Pin, time::{ Duration, Instant },
+		                       sync::atomic::{ AtomicBool, AtomicUsize, Ordering }                          },
 
 		// tokio::prelude::Future allows to use .then, but I imagine there is a better way...
 		//
-		tokio             :: { codec::{ Framed, Decoder }, io::{AsyncRead, AsyncWrite}                      },
+		tokio             :: { codec::{ Framed, Decoder, Encoder }, io::{AsyncRead, AsyncWrite}             },
 		tokio::prelude    :: { Future as _, stream::{ SplitSink, SplitStream, Stream } },
 		tokio_async_await :: { await as awaits, stream::StreamExt, sink::SinkExt                            },
 		tokio_serde_cbor  :: { Codec                                                                        },
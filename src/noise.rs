@@ -0,0 +1,472 @@
+//! An encrypted and authenticated session layer for links that leave the machine (tcp, websockets).
+//! It wraps any AsyncRead + AsyncWrite, so the result can be handed to IpcPeer like the bare connection.
+//
+use crate :: { import::*, EkkeIoError };
+
+use std   :: { io::{ self, Read, Write }               };
+use snow  :: { Builder, Session, params::NoiseParams   };
+use tokio :: { io::{ read_exact, write_all }           };
+
+
+/// XX: both sides transmit their static key during the handshake, so both can be authenticated.
+///
+const PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// The biggest noise message, by specification.
+///
+const MAX_MESSAGE: usize = 65535;
+
+/// Room for the authentication tag that gets added to every message.
+///
+const MAX_PLAINTEXT: usize = MAX_MESSAGE - 16;
+
+
+fn params() -> NoiseParams
+{
+	// This is a constant that is known to parse.
+	//
+	PATTERN.parse().unwrap()
+}
+
+
+
+/// The static keys for a noise session.
+///
+#[ derive( Clone ) ]
+//
+pub struct NoiseConfig
+{
+	/// Our static private key.
+	///
+	pub private_key: Vec<u8>,
+
+	/// The static public keys of peers we are willing to talk to. The connection is refused if the peer
+	/// presents any other key. An empty list refuses everybody.
+	///
+	pub trusted_keys: Vec< Vec<u8> >,
+}
+
+
+impl fmt::Debug for NoiseConfig
+{
+	fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result
+	{
+		write!( f, "NoiseConfig {{ private_key: <hidden>, trusted_keys: {} }}", self.trusted_keys.len() )
+	}
+}
+
+
+/// A static keypair, see `NoiseConfig::generate_keypair`.
+///
+pub struct Keypair
+{
+	pub private: Vec<u8>,
+	pub public : Vec<u8>,
+}
+
+
+impl NoiseConfig
+{
+	/// Generate a new static keypair. Give the public key to the peers that should trust you.
+	///
+	///     let server = NoiseConfig::generate_keypair()?;
+	///     let client = NoiseConfig::generate_keypair()?;
+	///
+	///     let server_config = NoiseConfig { private_key: server.private, trusted_keys: vec![ client.public ] };
+	///     let client_config = NoiseConfig { private_key: client.private, trusted_keys: vec![ server.public ] };
+	///
+	pub fn generate_keypair() -> Result< Keypair, EkkeIoError >
+	{
+		let keys = Builder::new( params() ).generate_keypair().map_err( noise_err )?;
+
+		Ok( Keypair { private: keys.private, public: keys.public } )
+	}
+}
+
+
+
+/// An encrypted connection. Create it with [`connect`](#method.connect) on the side that opened the
+/// connection and [`accept`](#method.accept) on the other side. Both return once the handshake is done
+/// and the peer presented a trusted key.
+///
+///     let stream = await!( NoiseStream::connect( tcp, config ) )?;
+///
+///     IpcPeer::create( |ctx| IpcPeer::new( stream, rpc, ctx.address(), log ) );
+///
+pub struct NoiseStream<S>
+
+	where S: AsyncRead + AsyncWrite
+
+{
+	inner     : S            ,
+	session   : Session      ,
+	remote_key: Vec<u8>      ,
+
+	// Ciphertext read from inner that doesn't make up a complete message yet.
+	//
+	incoming  : Vec<u8>      ,
+
+	// Decrypted data not read yet.
+	//
+	plain     : Vec<u8>      ,
+	plain_pos : usize        ,
+
+	// Ciphertext not written to inner yet.
+	//
+	outgoing  : Vec<u8>      ,
+}
+
+
+impl<S> NoiseStream<S>
+
+	where S: AsyncRead + AsyncWrite
+
+{
+	/// Run the handshake as the side that opened the connection.
+	///
+	pub async fn connect( stream: S, config: NoiseConfig ) -> Result< Self, EkkeIoError >
+	{
+		let session = Builder::new( params() )
+
+			.local_private_key( &config.private_key )
+			.build_initiator()
+			.map_err( noise_err )?
+		;
+
+		await!( Self::handshake( stream, session, config, true ) )
+	}
+
+
+	/// Run the handshake as the side that accepted the connection.
+	///
+	pub async fn accept( stream: S, config: NoiseConfig ) -> Result< Self, EkkeIoError >
+	{
+		let session = Builder::new( params() )
+
+			.local_private_key( &config.private_key )
+			.build_responder()
+			.map_err( noise_err )?
+		;
+
+		await!( Self::handshake( stream, session, config, false ) )
+	}
+
+
+	/// The static public key the peer authenticated with.
+	///
+	pub fn remote_key( &self ) -> &[u8]
+	{
+		&self.remote_key
+	}
+
+
+	async fn handshake( mut stream: S, mut session: Session, config: NoiseConfig, mut our_turn: bool ) -> Result< Self, EkkeIoError >
+	{
+		let mut buf = vec![ 0u8; MAX_MESSAGE ];
+
+		while !session.is_handshake_finished()
+		{
+			if our_turn
+			{
+				let len   = session.write_message( &[], &mut buf ).map_err( noise_err )?;
+				let frame = frame( &buf[ ..len ] );
+
+				stream = awaits!( write_all( stream, frame ) )?.0;
+			}
+
+			else
+			{
+				let (s, len) = awaits!( read_exact( stream, [0u8; 2] ) )?;
+				let  len     = usize::from( u16::from_be_bytes( len ) );
+
+				let (s, msg) = awaits!( read_exact( s, vec![ 0u8; len ] ) )?;
+
+				stream = s;
+				session.read_message( &msg, &mut buf ).map_err( noise_err )?;
+			}
+
+			our_turn = !our_turn;
+		}
+
+
+		let remote_key = session.get_remote_static().map( |k| k.to_vec() ).unwrap_or_default();
+
+		if !config.trusted_keys.iter().any( |k| *k == remote_key )
+		{
+			return Err( EkkeIoError::UntrustedPeer );
+		}
+
+		Ok( Self
+		{
+			inner     : stream,
+			session   : session.into_transport_mode().map_err( noise_err )?,
+			remote_key,
+			incoming  : Vec::new(),
+			plain     : Vec::new(),
+			plain_pos : 0,
+			outgoing  : Vec::new(),
+		})
+	}
+
+
+	/// Write as much pending ciphertext as inner takes.
+	///
+	fn drain( &mut self ) -> io::Result<()>
+	{
+		while !self.outgoing.is_empty()
+		{
+			match self.inner.write( &self.outgoing )?
+			{
+				0 => return Err( io::ErrorKind::WriteZero.into() ),
+				n => { self.outgoing.drain( ..n ); }
+			}
+		}
+
+		Ok(())
+	}
+}
+
+
+
+/// Prefix a noise message with it's length.
+///
+fn frame( msg: &[u8] ) -> Vec<u8>
+{
+	let mut out = Vec::with_capacity( msg.len() + 2 );
+
+	// Noise messages are never longer than u16::MAX.
+	//
+	out.extend_from_slice( &( msg.len() as u16 ).to_be_bytes() );
+	out.extend_from_slice( msg );
+
+	out
+}
+
+
+fn noise_err( e: snow::SnowError ) -> EkkeIoError
+{
+	EkkeIoError::Encryption( e.to_string() )
+}
+
+
+fn io_err( e: snow::SnowError ) -> io::Error
+{
+	io::Error::new( io::ErrorKind::InvalidData, noise_err( e ).to_string() )
+}
+
+
+
+impl<S> io::Read for NoiseStream<S>
+
+	where S: AsyncRead + AsyncWrite
+
+{
+	fn read( &mut self, buf: &mut [u8] ) -> io::Result<usize>
+	{
+		loop
+		{
+			if self.plain_pos < self.plain.len()
+			{
+				let available = &self.plain[ self.plain_pos.. ];
+				let n         = available.len().min( buf.len() );
+
+				buf[ ..n ].copy_from_slice( &available[ ..n ] );
+				self.plain_pos += n;
+
+				return Ok( n );
+			}
+
+
+			// Decrypt the next message if we have all of it.
+			//
+			if self.incoming.len() >= 2
+			{
+				let len = usize::from( u16::from_be_bytes([ self.incoming[0], self.incoming[1] ]) );
+
+				if self.incoming.len() >= 2 + len
+				{
+					self.plain.resize( MAX_MESSAGE, 0 );
+
+					let n = self.session.read_message( &self.incoming[ 2..2+len ], &mut self.plain ).map_err( io_err )?;
+
+					self.plain.truncate( n );
+					self.plain_pos = 0;
+					self.incoming.drain( ..2+len );
+
+					continue;
+				}
+			}
+
+
+			let mut chunk = [0u8; 8192];
+
+			match self.inner.read( &mut chunk )?
+			{
+				0 if self.incoming.is_empty() => return Ok( 0 ),
+				0                             => return Err( io::ErrorKind::UnexpectedEof.into() ),
+				n                             => self.incoming.extend_from_slice( &chunk[ ..n ] ),
+			}
+		}
+	}
+}
+
+
+
+impl<S> io::Write for NoiseStream<S>
+
+	where S: AsyncRead + AsyncWrite
+
+{
+	fn write( &mut self, buf: &[u8] ) -> io::Result<usize>
+	{
+		// Don't take more before the last message is out, that's our backpressure.
+		//
+		self.drain()?;
+
+		let take    = buf.len().min( MAX_PLAINTEXT );
+		let mut msg = vec![ 0u8; MAX_MESSAGE ];
+		let len     = self.session.write_message( &buf[ ..take ], &mut msg ).map_err( io_err )?;
+
+		self.outgoing = frame( &msg[ ..len ] );
+
+		// The data is ours now, if inner isn't ready, the next write or flush will push it out.
+		//
+		match self.drain()
+		{
+			Err( ref e ) if e.kind() == io::ErrorKind::WouldBlock => {}
+			Err( e ) => return Err( e ),
+			Ok ( _ ) => {}
+		}
+
+		Ok( take )
+	}
+
+
+	fn flush( &mut self ) -> io::Result<()>
+	{
+		self.drain()?;
+		self.inner.flush()
+	}
+}
+
+
+
+impl<S> AsyncRead for NoiseStream<S> where S: AsyncRead + AsyncWrite {}
+
+
+impl<S> AsyncWrite for NoiseStream<S>
+
+	where S: AsyncRead + AsyncWrite
+
+{
+	fn shutdown( &mut self ) -> tokio::prelude::Poll<(), io::Error>
+	{
+		match self.drain()
+		{
+			Err( ref e ) if e.kind() == io::ErrorKind::WouldBlock => return Ok( tokio::prelude::Async::NotReady ),
+			Err( e ) => return Err( e ),
+			Ok ( _ ) => {}
+		}
+
+		self.inner.shutdown()
+	}
+}
+
+
+
+#[ cfg( test ) ]
+//
+mod tests
+{
+	use super::*;
+
+	use std     :: { os::unix::net::UnixStream };
+	use futures :: { executor::block_on        };
+
+
+	/// An in memory duplex. A blocking socket never returns WouldBlock, so it can serve as
+	/// AsyncRead + AsyncWrite without a reactor.
+	///
+	struct Duplex( UnixStream );
+
+	impl Read  for Duplex { fn read ( &mut self, buf: &mut [u8] ) -> io::Result<usize> { self.0.read ( buf ) } }
+	impl Write for Duplex { fn write( &mut self, buf: &    [u8] ) -> io::Result<usize> { self.0.write( buf ) }
+	                        fn flush( &mut self                 ) -> io::Result<()>    { self.0.flush(     ) } }
+
+	impl AsyncRead  for Duplex {}
+	impl AsyncWrite for Duplex
+	{
+		fn shutdown( &mut self ) -> tokio::prelude::Poll<(), io::Error> { Ok( tokio::prelude::Async::Ready(()) ) }
+	}
+
+
+	type Outcome = Result< NoiseStream<Duplex>, EkkeIoError >;
+
+
+	/// Run the handshake, the client on a thread of it's own.
+	///
+	fn handshake( server: NoiseConfig, client: NoiseConfig ) -> ( Outcome, Outcome )
+	{
+		let ( a, b ) = UnixStream::pair().unwrap();
+
+		let client = std::thread::spawn( move || block_on( NoiseStream::connect( Duplex( a ), client ) ) );
+		let server = block_on( NoiseStream::accept( Duplex( b ), server ) );
+
+		( server, client.join().unwrap() )
+	}
+
+
+	#[ test ]
+	//
+	fn trusted_peers_talk()
+	{
+		let server = NoiseConfig::generate_keypair().unwrap();
+		let client = NoiseConfig::generate_keypair().unwrap();
+
+		let ( s, c ) = handshake
+		(
+			NoiseConfig { private_key: server.private, trusted_keys: vec![ client.public.clone() ] },
+			NoiseConfig { private_key: client.private, trusted_keys: vec![ server.public.clone() ] },
+		);
+
+		let mut s = s.unwrap();
+		let mut c = c.unwrap();
+
+		assert_eq!( s.remote_key(), &client.public[..] );
+		assert_eq!( c.remote_key(), &server.public[..] );
+
+		c.write_all( b"hello" ).unwrap();
+		c.flush().unwrap();
+
+		let mut buf = [0u8; 5];
+		s.read_exact( &mut buf ).unwrap();
+
+		assert_eq!( &buf, b"hello" );
+	}
+
+
+	#[ test ]
+	//
+	fn untrusted_key_is_refused()
+	{
+		let server   = NoiseConfig::generate_keypair().unwrap();
+		let client   = NoiseConfig::generate_keypair().unwrap();
+		let stranger = NoiseConfig::generate_keypair().unwrap();
+
+		let ( s, c ) = handshake
+		(
+			NoiseConfig { private_key: server.private, trusted_keys: vec![ stranger.public ] },
+			NoiseConfig { private_key: client.private, trusted_keys: vec![ server.public   ] },
+		);
+
+		match s
+		{
+			Err( EkkeIoError::UntrustedPeer ) => {}
+			_                                 => panic!( "the server should refuse a key it doesn't trust" ),
+		}
+
+		// The client trusts the server, so it's side of the handshake succeeds.
+		//
+		assert!( c.is_ok() );
+	}
+}
@@ -0,0 +1,220 @@
+//! Who is on the other side of a connection. IpcPeer attaches this to every message it receives,
+//! so Rpc can tell callers apart and services can be restricted to certain callers. Services that want
+//! to know who called them can take their message wrapped in a [`Caller`](struct.Caller.html).
+//
+use crate :: { import::*, ConnID, IpcMessage };
+
+
+/// Identifies the connection an incoming message arrived on.
+///
+#[ derive( Debug, Clone, PartialEq, Eq ) ]
+//
+pub struct PeerInfo
+{
+	/// Unique per IpcPeer, so it identifies a connection, not a process.
+	///
+	pub id: ConnID,
+
+	/// The credentials of the process on the other end, if the transport can tell us.
+	/// Currently only available for unix domain sockets on linux and android.
+	///
+	pub credentials: Option< PeerCredentials >,
+}
+
+
+
+/// The process on the other end of a unix domain socket, as reported by the kernel (SO_PEERCRED).
+/// The values are those of the peer at the time it connected, so they can't be spoofed by the peer.
+///
+#[ derive( Debug, Clone, Copy, PartialEq, Eq, Hash ) ]
+//
+pub struct PeerCredentials
+{
+	pub pid: i32,
+	pub uid: u32,
+	pub gid: u32,
+}
+
+
+impl PeerCredentials
+{
+	/// Ask the kernel who is connected to this socket. IpcPeer does this itself for every connection that
+	/// has a file descriptor, you only need it if you want to look before handing over the socket.
+	///
+	#[ cfg( any( target_os = "linux", target_os = "android" ) ) ]
+	//
+	pub fn of( socket: &impl std::os::unix::io::AsRawFd ) -> std::io::Result< Self >
+	{
+		use nix::sys::socket::{ getsockopt, sockopt::PeerCredentials as PeerCred };
+
+		let creds = getsockopt( socket.as_raw_fd(), PeerCred )
+
+			.map_err( |e| std::io::Error::new( std::io::ErrorKind::Other, e ) )?
+		;
+
+		Ok( Self { pid: creds.pid(), uid: creds.uid(), gid: creds.gid() } )
+	}
+}
+
+
+
+/// Find out who is on the other end of a connection, for transports where the kernel can tell us.
+///
+pub(crate) trait ReadCredentials
+{
+	fn peer_credentials( &self ) -> Option< PeerCredentials >;
+}
+
+
+impl<S> ReadCredentials for S
+{
+	default fn peer_credentials( &self ) -> Option< PeerCredentials > { None }
+}
+
+
+#[ cfg( any( target_os = "linux", target_os = "android" ) ) ]
+//
+impl<S> ReadCredentials for S where S: std::os::unix::io::AsRawFd
+{
+	// Fails for sockets that aren't unix domain sockets, they just don't get credentials.
+	//
+	fn peer_credentials( &self ) -> Option< PeerCredentials > { PeerCredentials::of( self ).ok() }
+}
+
+
+
+/// Wrap your service message in this to learn who called it. Rpc fills in the connection the request came in on.
+/// On the wire it's just the message, and the service keeps the name of M, so callers don't see a difference.
+///
+///     impl Handler< Caller<RegisterApplication> > for Ekke
+///     {
+///     	type Result = IpcMessage;
+///
+///     	fn handle( &mut self, req: Caller<RegisterApplication>, _ctx: &mut Context<Self> ) -> Self::Result
+///     	{
+///     		let uid = req.peer.and_then( |p| p.credentials ).map( |c| c.uid );
+///     		...
+///     	}
+///     }
+///
+/// In your service map: `"RegisterApplication" => rpc.deser_into::< Caller<RegisterApplication> >( msg, ipc_peer )`.
+///
+#[ derive( Debug, Clone ) ]
+//
+pub struct Caller<M>
+{
+	/// The connection the request came in on. Always set by Rpc.
+	///
+	pub peer: Option< PeerInfo >,
+
+	pub msg: M,
+}
+
+
+impl<M> Message for Caller<M> where M: Message< Result = IpcMessage >
+{
+	type Result = IpcMessage;
+}
+
+
+impl<M> TypeName for Caller<M> where M: TypeName
+{
+	fn fmt( f: &mut fmt::Formatter ) -> fmt::Result
+	{
+		M::fmt( f )
+	}
+}
+
+
+impl<'de, M> Deserialize<'de> for Caller<M> where M: Deserialize<'de>
+{
+	fn deserialize<D>( deserializer: D ) -> Result< Self, D::Error > where D: serde::Deserializer<'de>
+	{
+		M::deserialize( deserializer ).map( |msg| Caller { peer: None, msg } )
+	}
+}
+
+
+
+/// Lets Rpc hand the peer to services that asked for it with Caller, and do nothing for other messages.
+///
+pub(crate) trait SetPeer
+{
+	fn set_peer( &mut self, peer: &Option< PeerInfo > );
+}
+
+
+impl<T> SetPeer for T
+{
+	default fn set_peer( &mut self, _peer: &Option< PeerInfo > ) {}
+}
+
+
+impl<M> SetPeer for Caller<M>
+{
+	fn set_peer( &mut self, peer: &Option< PeerInfo > )
+	{
+		self.peer = peer.clone();
+	}
+}
+
+
+
+#[ cfg( test ) ]
+//
+mod tests
+{
+	use super::*;
+
+
+	#[ cfg( any( target_os = "linux", target_os = "android" ) ) ]
+	#[ test ]
+	//
+	fn reads_credentials_from_socket()
+	{
+		let ( ours, _theirs ) = std::os::unix::net::UnixStream::pair().unwrap();
+
+		let creds = ours.peer_credentials().expect( "a unix socket has credentials" );
+
+		assert_eq!( creds.pid, std::process::id() as i32 );
+	}
+
+
+	#[ test ]
+	//
+	fn no_credentials_without_socket()
+	{
+		assert_eq!( Vec::<u8>::new().peer_credentials(), None );
+	}
+
+
+	#[ test ]
+	//
+	fn caller_is_transparent()
+	{
+		let data = serde_cbor::to_vec( &5u32 ).unwrap();
+		let peer = Some( PeerInfo { id: ConnID::new(), credentials: None } );
+
+		let mut caller: Caller<u32> = des( &data ).unwrap();
+
+		assert_eq!( caller.msg , 5    );
+		assert_eq!( caller.peer, None );
+
+		caller.set_peer( &peer );
+
+		assert_eq!( caller.peer, peer );
+		assert_eq!( Caller::<u32>::type_name(), u32::type_name() );
+	}
+
+
+	#[ test ]
+	//
+	fn set_peer_ignores_plain_messages()
+	{
+		let mut plain = 5u32;
+
+		plain.set_peer( &Some( PeerInfo { id: ConnID::new(), credentials: None } ) );
+
+		assert_eq!( plain, 5 );
+	}
+}
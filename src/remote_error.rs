@@ -0,0 +1,164 @@
+use crate       :: { import::*, ConnID, EkkeIoError, IpcMessage, MessageType };
+use serde_bytes :: { ByteBuf                                                };
+
+
+/// What kind of failure a [`RemoteError`](struct.RemoteError.html) describes, so callers can act on it
+/// without matching on the message.
+///
+#[ derive( Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize ) ]
+//
+pub enum ErrorCode
+{
+	/// There is no service by that name.
+	///
+	NotFound,
+
+	/// The request could not be deserialized or was refused as invalid.
+	///
+	BadRequest,
+
+	/// The caller is not allowed to call this service.
+	///
+	PermissionDenied,
+
+	/// None of the versions of the service are within the range the caller asked for.
+	///
+	VersionMismatch,
+
+	/// The caller sent too many requests.
+	///
+	RateLimited,
+
+	/// The service has too many requests to handle, try again later.
+	///
+	Overloaded,
+
+	/// The service or the connection is not available, eg. because it's shutting down.
+	///
+	Unavailable,
+
+	/// Something went wrong while handling the request.
+	///
+	Internal,
+
+	/// An error defined by the application, look at the detail for more information.
+	///
+	Application,
+}
+
+
+
+/// The error that goes over the wire when a request fails. Services can send it back in a message of type
+/// `MessageType::Error` and callers receive it as `EkkeIoError::IpcError`.
+///
+/// Applications can attach their own error type as detail:
+///
+///     #[ derive( Serialize, Deserialize ) ]
+///     //
+///     struct NoSuchUser { name: String }
+///
+///     // In the service
+///     //
+///     RemoteError::new( ErrorCode::Application, "GetUser", "no such user" )
+///
+///     	.with_detail( &NoSuchUser { name } )
+///     	.into_message( conn_id )
+///     ;
+///
+///     // In the caller
+///     //
+///     if let Err( EkkeIoError::IpcError( remote ) ) = response
+///     {
+///     	let detail: Option<NoSuchUser> = remote.detail()?;
+///     }
+///
+#[ derive( Debug, Clone, PartialEq, Eq, Serialize, Deserialize ) ]
+//
+pub struct RemoteError
+{
+	pub code   : ErrorCode,
+	pub message: String   ,
+
+	/// The service that failed.
+	///
+	pub service: String   ,
+
+	/// Cbor encoded application specific detail, see [`detail`](#method.detail).
+	///
+	#[ serde( default ) ]
+	//
+	pub detail : Option< ByteBuf >,
+}
+
+
+impl RemoteError
+{
+	pub fn new( code: ErrorCode, service: impl Into<String>, message: impl Into<String> ) -> Self
+	{
+		Self { code, service: service.into(), message: message.into(), detail: None }
+	}
+
+
+	/// Describe a local error to a remote peer.
+	///
+	pub fn from_error( service: impl Into<String>, error: &EkkeIoError ) -> Self
+	{
+		let code = match error
+		{
+			// Pass on errors that are already structured, eg. returned by an interceptor.
+			//
+			EkkeIoError::IpcError( remote ) => return remote.clone(),
+
+			EkkeIoError::NoHandlerForService( _ ) => ErrorCode::NotFound        ,
+			EkkeIoError::PermissionDenied   ( _ ) => ErrorCode::PermissionDenied,
+			EkkeIoError::VersionMismatch    ( .. ) => ErrorCode::VersionMismatch ,
+			EkkeIoError::RateLimited        ( _ ) => ErrorCode::RateLimited     ,
+			EkkeIoError::Overloaded         ( _ ) => ErrorCode::Overloaded      ,
+			EkkeIoError::ServiceUnavailable ( _ ) |
+			EkkeIoError::ShuttingDown       ( _ ) |
+			EkkeIoError::ConnectionClosed         |
+			EkkeIoError::QueueFull                => ErrorCode::Unavailable     ,
+			_                                     => ErrorCode::Internal        ,
+		};
+
+		Self::new( code, service, error.to_string() )
+	}
+
+
+	/// Attach an application specific detail. It is serialized with cbor.
+	///
+	pub fn with_detail( mut self, detail: &impl Serialize ) -> Self
+	{
+		self.detail = serde_cbor::to_vec( detail ).ok().map( ByteBuf::from );
+		self
+	}
+
+
+	/// Decode the detail as the application error type you expect. Returns None if there is no detail.
+	///
+	pub fn detail<T: DeserializeOwned>( &self ) -> Result< Option<T>, Error >
+	{
+		match &self.detail
+		{
+			Some( detail ) => Ok( Some( des( detail )? ) ),
+			None           => Ok( None ),
+		}
+	}
+
+
+	/// Wrap it in a message to send back in response to the request with this conn_id.
+	///
+	pub fn into_message( self, conn_id: ConnID ) -> IpcMessage
+	{
+		IpcMessage::new( self.service.clone(), self, MessageType::Error, conn_id )
+	}
+}
+
+
+impl fmt::Display for RemoteError
+{
+	fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result
+	{
+		write!( f, "{:?} in service {}: {}", self.code, self.service, self.message )
+	}
+}
@@ -9,14 +9,58 @@ use crate::
 	  ConnID          ,
 	  IpcRequestIn    ,
 	  IpcRequestOut   ,
+	  IpcBatchOut     ,
+	  Batch           ,
 	  IpcResponse     ,
 	  IpcError        ,
 	  IpcMessage      ,
 	  RegisterService ,
+	  Shutdown        ,
+	  PeerInfo        ,
+	  RemoteError     ,
+	  ErrorCode       ,
+	  peer_info::SetPeer,
 };
 
 
 pub(crate) mod register_service;
+pub(crate) mod policy          ;
+pub(crate) mod service         ;
+pub(crate) mod rate_limit      ;
+pub(crate) mod concurrency     ;
+pub(crate) mod interceptor     ;
+pub(crate) mod introspection   ;
+pub(crate) mod version         ;
+pub(crate) mod system          ;
+pub(crate) mod retry           ;
+
+#[ cfg( feature = "schema" ) ] pub(crate) mod schema;
+
+pub use policy    ::{ Policy, RequestContext, Identity, GrantRole, RevokeRole };
+pub(crate) use policy::PeerClosed;
+pub use rate_limit::{ RateLimit, RpcStats, GetRpcStats                     };
+pub use concurrency::ConcurrencyLimit;
+pub use interceptor::Interceptor;
+pub use service    ::Balance;
+pub use introspection::{ ServiceInfo, LIST_SERVICES };
+pub use version      ::{ VersionRange, Candidate    };
+pub use system       ::{ ConfigureRpc               };
+pub use retry        ::{ RetryPolicy                };
+
+#[ cfg( feature = "schema" ) ] pub use schema::{ DescribeService, ExportSchema, Schema, LIST_SCHEMAS };
+
+use rate_limit ::TokenBucket;
+use concurrency::{ Load, Admission, Slot };
+use interceptor::Chain;
+use retry      ::{ SeenMap, Responses, answered, seen_key };
+
+
+/// The response to an outgoing request.
+///
+pub(crate) type Pending = Pin<Box< dyn StdFuture< Output = Result<IpcResponse, EkkeIoError> > + Send >>;
+
+use service::{ Service, Member, ServiceStopped };
+use register_service::UnregisterService;
 
 
 /// Rpc acts as an intermediary between your actors and IpcPeer. By registering your services with rpc, it will
@@ -24,15 +68,86 @@ pub(crate) mod register_service;
 /// a request to a response. When you send a IpcRequestOut message to Rpc, you will get back a future that will
 /// resolve to the reponse from a remote application.
 ///
+/// Every service has a [`Policy`](enum.Policy.html) that decides who may call it. It's checked before the request is
+/// deserialized, and callers that aren't allowed get a `PermissionDenied` error.
+///
+/// Incoming requests can be rate limited per peer and per service, see `with_peer_rate_limit` and
+/// `with_service_rate_limit`. Requests over the limit get a `RateLimited` error. Counters are available
+/// through [`GetRpcStats`](struct.GetRpcStats.html).
+///
+/// Cross cutting concerns like logging, metrics or validation can be handled for all services at once with
+/// an [`Interceptor`](trait.Interceptor.html).
+///
+/// To keep a slow service from piling up requests, limit how many it handles at once with
+/// `with_concurrency_limit`.
+///
+/// Every Rpc answers requests for [`LIST_SERVICES`](constant.LIST_SERVICES.html) with the services it provides,
+/// so peers can find out what they can call, see `list_services`.
+///
+/// To save round trips, several requests can go out in one frame with [`IpcBatchOut`](struct.IpcBatchOut.html).
+/// You get a result per request, in the same order.
+///
+/// Rpc is Send, so you can run it on an arbiter of it's own, and IpcPeers on other arbiters can share it.
+/// Services can live on any arbiter, including a `SyncArbiter`, see `RegisterService::of`.
+///
+///     let rpc = Arbiter::start( move |_| Rpc::new( log, crate::service_map ) );
+///
+/// If your application only needs one, you can also use the SystemService: `Rpc::from_registry()`,
+/// see [`ConfigureRpc`](struct.ConfigureRpc.html).
+///
+/// When you send it a [`Shutdown`](struct.Shutdown.html), Rpc refuses new requests and waits for the responses to
+/// requests it sent out until the deadline, after which the remaining ones fail with `EkkeIoError::ShuttingDown`.
+///
 pub struct Rpc
 {
-	handlers : HashMap< TypeId, Box< dyn Any > >                                                              ,
-	responses: Rc<RefCell< HashMap< ConnID, channel::oneshot::Sender< Result<IpcResponse, EkkeIoError> > > >> ,
+	handlers : HashMap< TypeId, Service >                                                                     ,
+	roles    : HashMap< Identity, HashSet<String> >                                                           ,
+	responses: Responses                                                                                      ,
 	log      : Logger                                                                                         ,
 	matcher  : fn( &Self, Logger, IpcMessage, Recipient< IpcMessage > )                                       ,
+	closing  : bool                                                                                           ,
+
+	peer_limit     : Option< RateLimit >                                                                      ,
+	service_limits : HashMap< String, RateLimit >                                                             ,
+	peer_buckets   : HashMap< ConnID, TokenBucket >                                                           ,
+	service_buckets: HashMap< String, TokenBucket >                                                           ,
+	stats          : RpcStats                                                                                 ,
+	loads          : HashMap< String, Arc<Mutex< Load >> >                                                    ,
+	interceptors   : Chain                                                                                    ,
+	addr           : Option< Addr<Rpc> >                                                                      ,
+	next_service   : usize                                                                                    ,
+	introspection  : Option< Policy >                                                                         ,
+	retries        : HashMap< String, RetryPolicy >                                                           ,
+	at_most_once   : HashMap< String, Duration >                                                              ,
+	seen           : SeenMap                                                                                  ,
+
+	#[ cfg( feature = "schema" ) ]
+	//
+	schemas        : HashMap< TypeId, DescribeService >                                                       ,
 }
 
-impl Actor for Rpc { type Context = Context<Self>; }
+
+impl Actor for Rpc
+{
+	type Context = Context<Self>;
+
+	fn started( &mut self, ctx: &mut Self::Context )
+	{
+		// Service tasks need it to tell us about stopped service actors.
+		//
+		self.addr = Some( ctx.address() );
+
+		// Peers come and go, so forget the buckets that have filled up again.
+		//
+		ctx.run_interval( Duration::from_secs( 60 ), |rpc, _ctx|
+		{
+			rpc.peer_buckets.retain( |_, bucket| !bucket.is_full() );
+
+			let now = Instant::now();
+			rpc.seen.lock().retain( |_, seen| seen.keep( now ) );
+		});
+	}
+}
 
 
 
@@ -66,20 +181,228 @@ impl Rpc
 	///
 	pub fn new( log: Logger, matcher: fn( &Self, Logger, IpcMessage, Recipient< IpcMessage > ) ) -> Self
 	{
-		Self { handlers: HashMap::new(), responses: Rc::new( RefCell::new( HashMap::new() )), log, matcher }
+		Self
+		{
+			handlers : HashMap::new()                           ,
+			roles    : HashMap::new()                           ,
+			responses: Arc::new( Mutex::new( HashMap::new() ))   ,
+			log                                                 ,
+			matcher                                             ,
+			closing  : false                                    ,
+
+			peer_limit     : None                ,
+			service_limits : HashMap::new()      ,
+			peer_buckets   : HashMap::new()      ,
+			service_buckets: HashMap::new()      ,
+			stats          : RpcStats::default() ,
+			loads          : HashMap::new()      ,
+			interceptors   : Arc::new( Vec::new() ),
+			addr           : None                ,
+			next_service   : 0                   ,
+			introspection  : Some( Policy::Public ),
+			retries        : HashMap::new()      ,
+			at_most_once   : HashMap::new()      ,
+			seen           : Arc::new( Mutex::new( HashMap::new() ) ),
+
+			#[ cfg( feature = "schema" ) ]
+			//
+			schemas        : HashMap::new()      ,
+		}
+	}
+
+
+	/// Limit the requests every connected peer can make, over all services.
+	///
+	///     let rpc = Rpc::new( log, crate::service_map )
+	///
+	///        .with_peer_rate_limit   (                        RateLimit{ per_second: 50.0, burst: 100 } )
+	///        .with_service_rate_limit( "RegisterApplication", RateLimit{ per_second:  1.0, burst:   5 } )
+	///        .start();
+	///
+	pub fn with_peer_rate_limit( mut self, limit: RateLimit ) -> Self
+	{
+		self.peer_limit = Some( limit );
+		self
+	}
+
+
+	/// Limit the requests to a service, over all peers. The name is the service name as it comes in
+	/// over the wire.
+	///
+	pub fn with_service_rate_limit( mut self, service: &str, limit: RateLimit ) -> Self
+	{
+		self.service_limits.insert( service.to_string(), limit );
+		self
+	}
+
+
+	/// Who may ask for the list of services with [`list_services`](#method.list_services). Everybody
+	/// by default, None turns it off.
+	///
+	pub fn with_introspection( mut self, policy: Option< Policy > ) -> Self
+	{
+		self.introspection = policy;
+		self
+	}
+
+
+	/// Retry outgoing requests for a service that can safely be handled more than once. The name is the
+	/// service name as it goes over the wire.
+	///
+	pub fn with_retry( mut self, service: &str, policy: RetryPolicy ) -> Self
+	{
+		self.retries.insert( service.to_string(), policy );
+		self
+	}
+
+
+	/// Handle a request for a service at most once, for services that can't safely be called twice. Requests
+	/// that come in again from the same caller with the same conn_id within window, eg. because the caller retried
+	/// after losing the connection, get the response of the first one instead of being handled again. The caller is
+	/// recognized by uid when the transport tells us, otherwise by connection. Errors that might go away by
+	/// themselves aren't remembered, so the caller can try again.
+	///
+	pub fn with_at_most_once( mut self, service: &str, window: Duration ) -> Self
+	{
+		self.at_most_once.insert( service.to_string(), window );
+		self
+	}
+
+
+	/// Add an interceptor at the end of the chain. See [`Interceptor`](trait.Interceptor.html) for the
+	/// order in which they run.
+	///
+	pub fn with_interceptor( mut self, interceptor: impl Interceptor + 'static ) -> Self
+	{
+		// Requests in flight might still hold the old chain, eg. when reconfiguring the system Rpc.
+		//
+		let mut chain = self.interceptors.as_ref().clone();
+
+		chain.push( Arc::new( interceptor ) );
+
+		self.interceptors = Arc::new( chain );
+		self
+	}
+
+
+	/// Limit how many requests for a service are handled at the same time. The name is the service
+	/// name as it comes in over the wire.
+	///
+	///     let rpc = Rpc::new( log, crate::service_map )
+	///
+	///        .with_concurrency_limit( "Render", ConcurrencyLimit{ max_in_flight: 4, queue: 16 } )
+	///        .start();
+	///
+	pub fn with_concurrency_limit( mut self, service: &str, limit: ConcurrencyLimit ) -> Self
+	{
+		self.loads.insert( service.to_string(), Arc::new( Mutex::new( Load::new( limit ) ) ) );
+		self
+	}
+
+
+	/// Take a token from the buckets this request counts against. Returns the error to send back
+	/// if there is none left. Tokens are only taken if all buckets have one, so a request refused by
+	/// one bucket doesn't count against the other.
+	///
+	fn rate_limit( &mut self, msg: &IpcMessage ) -> Result< (), EkkeIoError >
+	{
+		let mut peer_bucket = match ( self.peer_limit, msg.peer.as_ref() )
+		{
+			( Some( limit ), Some( peer ) ) => Some( self.peer_buckets.entry( peer.id ).or_insert_with( || TokenBucket::new( limit ) ) ),
+			_                               => None,
+		};
+
+		if let Some( bucket ) = &mut peer_bucket
+		{
+			if !bucket.has_token()
+			{
+				self.stats.rate_limited_peer += 1;
+
+				return Err( EkkeIoError::RateLimited( msg.service.clone() ) );
+			}
+		}
+
+		let mut service_bucket = match self.service_limits.get( &msg.service )
+		{
+			Some( limit ) => Some( self.service_buckets.entry( msg.service.clone() ).or_insert_with( || TokenBucket::new( *limit ) ) ),
+			None          => None,
+		};
+
+		if let Some( bucket ) = &mut service_bucket
+		{
+			if !bucket.has_token()
+			{
+				*self.stats.rate_limited_service.entry( msg.service.clone() ).or_insert( 0 ) += 1;
+
+				return Err( EkkeIoError::RateLimited( msg.service.clone() ) );
+			}
+		}
+
+		// Both have a token, and nothing can take it in between.
+		//
+		if let Some( bucket ) = peer_bucket    { bucket.take(); }
+		if let Some( bucket ) = service_bucket { bucket.take(); }
+
+		Ok(())
+	}
+
+
+	/// All roles granted to the caller, through it's connection or it's uid.
+	///
+	fn roles_of( &self, peer: Option<&PeerInfo> ) -> Vec<String>
+	{
+		let peer = match peer
+		{
+			Some( peer ) => peer,
+			None         => return Vec::new(),
+		};
+
+		Identity::of( peer ).iter()
+
+			.filter_map( |id| self.roles.get( id ) )
+			.flat_map  ( |roles| roles.iter().cloned() )
+			.collect()
+	}
+
+
+	/// When shutting down, we can stop as soon as no more responses are expected.
+	///
+	fn stop_if_drained( &self, ctx: &mut Context<Self> )
+	{
+		if self.closing && self.responses.lock().is_empty()
+		{
+			ctx.stop();
+		}
+	}
+
+
+	/// The services Rpc answers itself.
+	///
+	fn builtin( service: &str ) -> Option< fn( &Self, IpcMessage, Recipient< IpcMessage > ) >
+	{
+		match service
+		{
+			LIST_SERVICES => Some( Self::introspect ),
+
+			#[ cfg( feature = "schema" ) ]
+			//
+			LIST_SCHEMAS  => Some( Self::export_schema ),
+
+			_             => None,
+		}
 	}
 
 
 	/// Send an error message back to the peer application over the ipc channel.
 	///
-	pub fn error_response( &self, service: String, error: String, addr: Recipient< IpcMessage >, conn_id: ConnID )
+	pub fn error_response( &self, error: RemoteError, addr: Recipient< IpcMessage >, conn_id: ConnID )
 	{
 		let log = self.log.clone();
 
 		Arbiter::spawn
 		(
 
-			addr.send( IpcMessage::new( service, error, MessageType::Error, conn_id ) )
+			addr.send( error.into_message( conn_id ) )
 
 				.then( move |r|
 				{
@@ -118,6 +441,57 @@ impl Rpc
 
 
 
+	/// Like deser_into, for services that have several versions side by side, each with it's own message type.
+	/// The request goes to the newest registered version within the range the caller asked for. If the caller
+	/// didn't ask for a version, that's the newest one. Versions nobody provides any more are skipped. Fails with
+	/// `VersionMismatch` if none fits.
+	///
+	///     "RegisterApplication" => rpc.deser_versioned( msg, ipc_peer, &[
+	///
+	///     	Candidate::of::< RegisterApplicationV1 >(),
+	///     	Candidate::of::< RegisterApplicationV2 >(),
+	///     ]),
+	///
+	pub fn deser_versioned( &self, msg: IpcMessage, ipc_peer: Recipient< IpcMessage >, candidates: &[Candidate] )
+	{
+		let wanted = msg.version;
+
+		// Services that stopped or were unregistered are kept without members.
+		//
+		let provided = |c: &Candidate| self.handlers.get( &c.type_id ).filter( |s| !s.members.is_empty() ).map( |s| s.version );
+
+		let best = candidates.iter()
+
+			.filter_map( |c| provided( c ).map( |version| ( c, version ) ) )
+			.filter    ( |( _, version )| wanted.map( |w| w.contains( *version ) ).unwrap_or( true ) )
+			.max_by_key( |( _, version )| *version )
+		;
+
+		match best
+		{
+			Some(( candidate, _ )) => ( candidate.deser )( self, msg, ipc_peer ),
+
+			None =>
+			{
+				let available: Vec<u32> = candidates.iter().filter_map( provided ).collect();
+				let stopped              = candidates.iter().any( |c| self.handlers.contains_key( &c.type_id ) );
+
+				// Without any registered version this is just an unknown service, unless it was provided before.
+				//
+				let error = match wanted
+				{
+					Some( wanted ) if !available.is_empty() => EkkeIoError::VersionMismatch( msg.service.clone(), wanted.to_string(), format!( "{:?}", available ) ),
+					_ if available.is_empty() && stopped    => EkkeIoError::ServiceUnavailable( msg.service.clone() ),
+					_                                       => EkkeIoError::NoHandlerForService( msg.service.clone() ),
+				};
+
+				self.error_response( RemoteError::from_error( msg.service.clone(), &error ), ipc_peer, msg.conn_id );
+			}
+		}
+	}
+
+
+
 	/// Part of processing incoming requests, this method needs to be called from the callback function
 	/// you pass to the constructor of this class. Look at the documentation on `new` for an example.
 	///
@@ -140,9 +514,42 @@ impl Rpc
 			//
 			Some( service ) =>
 			{
+				// Check whether the caller is allowed to use this service before we do any work
+				//
+				let roles   = self.roles_of( msg.peer.as_ref() );
+				let context = RequestContext { service: &msg.service, peer: msg.peer.as_ref(), roles: &roles };
+
+				if !service.policy.allows( &context )
+				{
+					warn!( self.log, "Rpc: refusing call to service {} from peer: {:?}", &msg.service, &msg.peer );
+
+					self.error_response
+					(
+						  RemoteError::from_error( msg.service.clone(), &EkkeIoError::PermissionDenied( msg.service.clone() ) )
+						, ipc_peer
+						, msg.conn_id
+					);
+
+					return;
+				}
+
+
+				// The caller might need a version we don't have.
+				//
+				if let Some( wanted ) = msg.version
+				{
+					if !wanted.contains( service.version )
+					{
+						let error = EkkeIoError::VersionMismatch( msg.service.clone(), wanted.to_string(), format!( "{:?}", [ service.version ] ) );
+
+						return self.error_response( RemoteError::from_error( msg.service.clone(), &error ), ipc_peer, msg.conn_id );
+					}
+				}
+
+
 				// Deserialize the payload
 				//
-				let de: INTO = match des( &msg.payload )
+				let mut de: INTO = match des( &msg.payload )
 				{
 					Ok ( data  ) => data,
 
@@ -150,10 +557,11 @@ impl Rpc
 					{
 						// If we can't deserialize, send an error message to the ipc peer application
 						//
+						let message = format!( "Rpc component could not deserialize your message for service:{} :{:?}", &msg.service, error );
+
 						self.error_response
 						(
-							  msg.service.clone()
-							, format!( "Rpc component could not deserialize your message for service:{} :{:?}", &msg.service, error )
+							  RemoteError::new( ErrorCode::BadRequest, msg.service.clone(), message )
 							, ipc_peer
 							, msg.conn_id
 						);
@@ -165,9 +573,27 @@ impl Rpc
 				};
 
 
+				de.set_peer( &msg.peer );
+
+
+				// Choose the actor if there is a pool.
+				//
+				let member = match service.pick()
+				{
+					Some( member ) => member,
+
+					None =>
+					{
+						let error = EkkeIoError::ServiceUnavailable( msg.service.clone() );
+
+						return self.error_response( RemoteError::from_error( msg.service.clone(), &error ), ipc_peer, msg.conn_id );
+					}
+				};
+
+
 				// Downcast our Any pointer
 				//
-				let recipient = match service.downcast_ref::< Recipient<INTO> >()
+				let recipient = match member.recipient.downcast_ref::< Recipient<INTO> >()
 				{
 					Some( recipient ) => recipient,
 					None              => Err( EkkeIoError::DowncastRecipientFailed( name.clone() ) ).unwraps( &self.log )
@@ -176,22 +602,109 @@ impl Rpc
 
 				// Send the message to the service actor and wait for a response to send back to the peer
 				//
-				let addr = recipient.clone();
+				let addr    = recipient.clone();
+				let load    = self.loads.get( &msg.service ).cloned();
+				let done    = load.clone();
+				let peer    = ipc_peer.clone();
+				let chain   = self.interceptors.clone();
+				let conn_id = msg.conn_id;
+				let rpc     = self.addr.clone();
+				let stopped = ServiceStopped { type_id: TypeId::of::<INTO>(), id: member.id };
+				let busy    = member.in_flight.clone();
+				let seen    = if self.at_most_once.contains_key( &msg.service ) { Some(( self.seen.clone(), seen_key( &msg ) )) } else { None };
+
+				let job = move ||
+				{
+					busy.fetch_add( 1, Ordering::SeqCst );
+
+					Arbiter::spawn( async move
+					{
+						// Gives our place back to the next request, however we leave.
+						//
+						let _slot = done.map( Slot );
+
+						let mut resp = match awaits!( addr.send( de ) )
+						{
+							Ok( resp ) => resp,
+
+							// The service actor is gone, stop sending it requests.
+							//
+							Err( MailboxError::Closed ) =>
+							{
+								warn!( log, "Rpc: the actor for service {} has stopped", &name );
+
+								if let Some( rpc ) = rpc { rpc.do_send( stopped ); }
+
+								RemoteError::from_error( name.clone(), &EkkeIoError::ServiceUnavailable( name.clone() ) ).into_message( conn_id )
+							}
+
+							Err( e ) =>
+							{
+								error!( log, "Rpc: mailbox error for service {}: {}", &name, e );
+
+								RemoteError::from_error( name.clone(), &EkkeIoError::ActixMailboxError( name.clone(), e ) ).into_message( conn_id )
+							}
+						};
+
+						for interceptor in chain.iter().rev()
+						{
+							if let Err( e ) = interceptor.on_response_out( &mut resp )
+							{
+								resp = RemoteError::from_error( name.clone(), &e ).into_message( conn_id );
+								break;
+							}
+						}
+
+						busy.fetch_sub( 1, Ordering::SeqCst );
+
+						if let Some(( seen, key )) = seen { answered( &seen, key, &resp ); }
+
+						// If the peer is gone there is nobody left to tell.
+						//
+						if let Err( e ) = awaits!( peer.send( resp ) )
+						{
+							debug!( log, "Rpc: could not return response for service {}: {}", &name, e );
+						}
+
+						Ok(())
 
-				Arbiter::spawn( async move
+					}.boxed().compat() )
+				};
+
+
+				self.remember( &msg );
+
+				let load = match load
 				{
-					let resp = awaits!( addr.send( de ) )
+					Some( load ) => load,
+					None         => return job(),
+				};
 
-						.map_err( |e| format_err!( "Rpc::Handler<IpcRequestIn> -> {}: mailbox error: {}", &name, e ) )
-					   .unwraps( &log )
-					;
+				// Bind the result first, so the lock is released before we run the job.
+				//
+				let admission = load.lock().admit( Box::new( job ) );
 
-					awaits!( ipc_peer.send( resp ) ).unwraps( &log );
+				match admission
+				{
+					Admission::Run( job ) => job(),
+					Admission::Queued     => trace!( self.log, "Rpc: queued request for service: {}", &msg.service ),
 
+					Admission::Refused    =>
+					{
+						debug!( self.log, "Rpc: service overloaded: {}", &msg.service );
 
-					Ok(())
+						// It wasn't handled, so it may be sent again.
+						//
+						self.seen.lock().remove( &seen_key( &msg ) );
 
-				}.boxed().compat() )
+						self.error_response
+						(
+							  RemoteError::from_error( msg.service.clone(), &EkkeIoError::Overloaded( msg.service.clone() ) )
+							, ipc_peer
+							, msg.conn_id
+						);
+					}
+				}
 			},
 
 			// There is no handler for this service, let the peer app know
@@ -200,7 +713,7 @@ impl Rpc
 			//
 			None => self.error_response
 
-				( msg.service.clone(), format!( "No handler is registered for service: {:?}", &msg.service ), ipc_peer, msg.conn_id )
+				( RemoteError::from_error( msg.service.clone(), &EkkeIoError::NoHandlerForService( msg.service.clone() ) ), ipc_peer, msg.conn_id )
 		}
 	}
 }
@@ -216,10 +729,64 @@ impl Handler<IpcRequestIn> for Rpc
 
 	/// Handle incoming IPC requests
 	///
-	fn handle( &mut self, msg: IpcRequestIn, _ctx: &mut Context<Self> ) -> Self::Result
+	fn handle( &mut self, mut msg: IpcRequestIn, _ctx: &mut Context<Self> ) -> Self::Result
 	{
 		debug!( &self.log, "Received incoming request: {}", &msg.ipc_msg.service );
 
+		self.stats.requests += 1;
+
+		if self.closing
+		{
+			let error = RemoteError::from_error( msg.ipc_msg.service, &EkkeIoError::ShuttingDown( "Rpc".into() ) );
+
+			return self.error_response( error, msg.ipc_peer, msg.ipc_msg.conn_id );
+		}
+
+		if self.duplicate( &msg.ipc_msg, &msg.ipc_peer ) { return; }
+
+		if let Err( error ) = self.rate_limit( &msg.ipc_msg )
+		{
+			debug!( self.log, "{}", &error );
+
+			return self.error_response( RemoteError::from_error( msg.ipc_msg.service, &error ), msg.ipc_peer, msg.ipc_msg.conn_id );
+		}
+
+		for interceptor in self.interceptors.iter()
+		{
+			if let Err( error ) = interceptor.on_request_in( &mut msg.ipc_msg )
+			{
+				debug!( self.log, "Rpc: interceptor refused request: {}", &error );
+
+				return self.error_response( RemoteError::from_error( msg.ipc_msg.service, &error ), msg.ipc_peer, msg.ipc_msg.conn_id );
+			}
+		}
+
+		if let Some( answer ) = Self::builtin( &msg.ipc_msg.service )
+		{
+			let service = msg.ipc_msg.service.clone();
+			let roles   = self.roles_of( msg.ipc_msg.peer.as_ref() );
+			let context = RequestContext { service: &service, peer: msg.ipc_msg.peer.as_ref(), roles: &roles };
+
+			return match &self.introspection
+			{
+				Some( policy ) if policy.allows( &context ) => answer( self, msg.ipc_msg, msg.ipc_peer ),
+
+				Some( _ ) =>
+				{
+					let error = EkkeIoError::PermissionDenied( service.clone() );
+
+					self.error_response( RemoteError::from_error( service, &error ), msg.ipc_peer, msg.ipc_msg.conn_id )
+				}
+
+				None =>
+				{
+					let error = EkkeIoError::NoHandlerForService( service.clone() );
+
+					self.error_response( RemoteError::from_error( service, &error ), msg.ipc_peer, msg.ipc_msg.conn_id )
+				}
+			};
+		}
+
 		// Give user supplied callback the the data, so they can identify the type for deserialization
 		//
 		(self.matcher)( self, self.log.new( o!( "fn" => "service_map" ) ), msg.ipc_msg, msg.ipc_peer );
@@ -237,22 +804,142 @@ impl Handler<IpcRequestOut> for Rpc
 
 	/// Handle outgoing RPC requests
 	///
-	fn handle( &mut self, mut msg: IpcRequestOut, _ctx: &mut Context<Self> ) -> Self::Result
+	fn handle( &mut self, msg: IpcRequestOut, _ctx: &mut Context<Self> ) -> Self::Result
 	{
+		match self.retries.get( &msg.ipc_msg.service )
+		{
+			Some( policy ) => ActixFuture::from( self.request_with_retry( msg, *policy ) ),
+			None           => ActixFuture::from( self.request( msg )                     ),
+		}
+	}
+}
+
+
+
+impl Rpc
+{
+	/// Send a request once.
+	///
+	pub(crate) fn request( &self, msg: IpcRequestOut ) -> Pending
+	{
+		if self.closing
+		{
+			return Box::pin( async { Err( EkkeIoError::ShuttingDown( "Rpc".into() ) ) } );
+		}
+
+		match self.expect( msg.ipc_msg )
+		{
+			Ok(( ipc_msg, pending )) =>
+			{
+				let conn_id = ipc_msg.conn_id;
+
+				// The response will never come, don't wait for it.
+				//
+				if msg.ipc_peer.do_send( ipc_msg ).is_err()
+				{
+					self.responses.lock().remove( &conn_id );
+
+					return Box::pin( async { Err( EkkeIoError::ConnectionClosed ) } );
+				}
+
+				pending
+			}
+
+			Err( error ) => Box::pin( async move { Err( error ) } ),
+		}
+	}
+
+
+	/// Run the interceptors on an outgoing request and get ready for the response. Returns the message
+	/// to send to the peer.
+	///
+	fn expect( &self, mut ipc_msg: IpcMessage ) -> Result< ( IpcMessage, Pending ), EkkeIoError >
+	{
+		for interceptor in self.interceptors.iter()
+		{
+			interceptor.on_request_out( &mut ipc_msg )?;
+		}
+
 		let (sender, receiver) = channel::oneshot::channel::< Result<IpcResponse, EkkeIoError> >();
 
-		self.responses.borrow_mut().insert( msg.ipc_msg.conn_id, sender );
+		self.responses.lock().insert( ipc_msg.conn_id, sender );
 
-		msg.ipc_msg.ms_type = MessageType::IpcRequestIn;
-		let _ = msg.ipc_peer.do_send( msg.ipc_msg );
+		ipc_msg.ms_type = MessageType::IpcRequestIn;
 
 
-		let log = self.log.clone();
+		let chain = self.interceptors.clone();
 
-		ActixFuture::from( async move
+		Ok(( ipc_msg, Box::pin( async move
 		{
-			await!( receiver ).unwraps( &log )
-		})
+			// The sender is dropped when the response can't come any more, eg. Rpc stopped.
+			//
+			let mut result = await!( receiver ).unwrap_or_else( |_| Err( EkkeIoError::ConnectionClosed ) );
+
+			for interceptor in chain.iter().rev()
+			{
+				interceptor.on_response_in( &mut result );
+			}
+
+			result
+		})))
+	}
+}
+
+
+
+/// Send several requests in one frame.
+///
+impl Handler<IpcBatchOut> for Rpc
+{
+	type Result = ActixFuture< Vec< Result<IpcResponse, EkkeIoError> > >;
+
+	fn handle( &mut self, msg: IpcBatchOut, _ctx: &mut Context<Self> ) -> Self::Result
+	{
+		let mut messages = Vec::with_capacity( msg.requests.len() );
+		let mut results  = Vec::with_capacity( msg.requests.len() );
+
+		for request in msg.requests
+		{
+			if self.closing
+			{
+				results.push( Box::pin( async { Err( EkkeIoError::ShuttingDown( "Rpc".into() ) ) } ) as Pending );
+				continue;
+			}
+
+			match self.expect( request )
+			{
+				Ok(( ipc_msg, pending )) =>
+				{
+					messages.push( ipc_msg );
+					results .push( pending );
+				}
+
+				Err( error ) => results.push( Box::pin( async move { Err( error ) } ) ),
+			}
+		}
+
+		if !messages.is_empty()
+		{
+			let conn_ids: Vec<ConnID> = messages.iter().map( |ipc_msg| ipc_msg.conn_id ).collect();
+			let batch                 = Batch { messages, batch_responses: msg.batch_responses };
+
+			// The connection is gone, nobody will answer these.
+			//
+			if msg.ipc_peer.do_send( IpcMessage::new( String::new(), batch, MessageType::Batch, ConnID::new() ) ).is_err()
+			{
+				let mut responses = self.responses.lock();
+
+				for conn_id in conn_ids
+				{
+					if let Some( sender ) = responses.remove( &conn_id )
+					{
+						let _ = sender.send( Err( EkkeIoError::ConnectionClosed ) );
+					}
+				}
+			}
+		}
+
+		ActixFuture::from( join_all( results ) )
 	}
 }
 
@@ -266,12 +953,19 @@ impl Handler<IpcResponse> for Rpc
 
 	/// Handle incoming Responses
 	///
-	fn handle( &mut self, msg: IpcResponse, _ctx: &mut Context<Self> ) -> Self::Result
+	fn handle( &mut self, msg: IpcResponse, ctx: &mut Context<Self> ) -> Self::Result
 	{
-		let mut borrow  = self.responses.borrow_mut();
-		let     channel = borrow.remove( &msg.ipc_msg.conn_id ).unwrap();
+		let channel = self.responses.lock().remove( &msg.ipc_msg.conn_id );
 
-		let     _       = channel.send( Ok( msg ) );
+		// A late answer to an attempt we already gave up on, or a duplicate.
+		//
+		match channel
+		{
+			Some( channel ) => { let _ = channel.send( Ok( msg ) ); }
+			None            => debug!( self.log, "Rpc: no request waiting for answer from service: {}", &msg.ipc_msg.service ),
+		}
+
+		self.stop_if_drained( ctx );
 	}
 }
 
@@ -285,12 +979,48 @@ impl Handler<IpcError> for Rpc
 
 	/// Handle incoming Errors
 	///
-	fn handle( &mut self, msg: IpcError, _ctx: &mut Context<Self> ) -> Self::Result
+	fn handle( &mut self, msg: IpcError, ctx: &mut Context<Self> ) -> Self::Result
+	{
+		let channel = self.responses.lock().remove( &msg.ipc_msg.conn_id );
+
+		// A late answer to an attempt we already gave up on, or a duplicate.
+		//
+		match channel
+		{
+			Some( channel ) => { let _ = channel.send( Err( msg.into() ) ); }
+			None            => debug!( self.log, "Rpc: no request waiting for answer from service: {}", &msg.ipc_msg.service ),
+		}
+
+		self.stop_if_drained( ctx );
+	}
+}
+
+
+
+/// Stop taking new requests and stop once all outstanding responses came in or the deadline passed.
+///
+impl Handler<Shutdown> for Rpc
+{
+	type Result = ();
+
+	fn handle( &mut self, msg: Shutdown, ctx: &mut Context<Self> ) -> Self::Result
 	{
-		let mut borrow  = self.responses.borrow_mut();
-		let     channel = borrow.remove( &msg.ipc_msg.conn_id ).unwrap();
+		if self.closing { return; }
 
-		let     _       = channel.send( Err( msg.into() ) );
+		info!( self.log, "Rpc: shutting down, waiting for {} responses", self.responses.lock().len() );
+
+		self.closing = true;
+		self.stop_if_drained( ctx );
+
+		ctx.run_later( msg.deadline, |rpc, ctx|
+		{
+			for ( _, channel ) in rpc.responses.lock().drain()
+			{
+				let _ = channel.send( Err( EkkeIoError::ShuttingDown( "Rpc".into() ) ) );
+			}
+
+			ctx.stop();
+		});
 	}
 }
 
@@ -305,26 +1035,191 @@ where
 
 	M: Send + Message<Result = IpcMessage> + 'static
 {
-	type Result = ();
+	type Result = Result< (), EkkeIoError >;
 
 
-	#[ allow( clippy::suspicious_else_formatting ) ]
-	//
 	fn handle( &mut self, msg: RegisterService<M>, _ctx: &mut Context<Self> ) -> Self::Result
 	{
-		if let Some( _service ) = self.handlers.remove( &msg.type_id )
+		self.next_service += 1;
+
+		let member = Member
 		{
-			crit!( self.log, "{}", EkkeIoError::DoubleServiceRegistration( format!( "{:?}", &msg.service ), msg.actor ) );
+			actor    : msg.actor.clone()        ,
+			recipient: Box::new( msg.recipient ),
+			id       : self.next_service        ,
+			in_flight: Arc::new( AtomicUsize::new( 0 ) ),
+		};
+
 
-			std::process::exit( 1 );
+		// A service nobody provides any more is free to take.
+		//
+		if let Some( existing ) = self.handlers.get_mut( &msg.type_id ).filter( |s| !s.members.is_empty() )
+		{
+			// Joining a pool.
+			//
+			if msg.pool.is_some() && existing.pool.is_some() && !msg.replace
+			{
+				debug!( self.log, "Rpc: actor {} joins the pool for service: {}", &msg.actor, &msg.service );
+
+				existing.members.push( member );
+				return Ok(());
+			}
+
+			if !msg.replace
+			{
+				let error = EkkeIoError::DoubleServiceRegistration( format!( "{}, provided by: {}", &msg.service, existing.actors() ), msg.actor );
+
+				error!( self.log, "{}", &error );
+
+				return Err( error );
+			}
+
+			info!( self.log, "Rpc: actor {} replaces {} for service: {}", &msg.actor, existing.actors(), &msg.service );
 		}
 
-		else
+
+		#[ cfg( feature = "schema" ) ]
+		//
+		self.describe::<M>();
+
+		self.handlers.insert( msg.type_id, Service
 		{
-			self.handlers.insert( msg.type_id, Box::new( msg.recipient ) );
+			name   : msg.service         ,
+			version: msg.version         ,
+			policy : msg.policy          ,
+
+			request_type : msg.request_type ,
+			response_type: msg.response_type,
+
+			members: vec![ member ]      ,
+			pool   : msg.pool            ,
+			cursor : AtomicUsize::new( 0 ),
+		});
+
+		Ok(())
+	}
+}
+
+
+
+impl Handler<UnregisterService> for Rpc
+{
+	type Result = Result< (), EkkeIoError >;
+
+	fn handle( &mut self, msg: UnregisterService, _ctx: &mut Context<Self> ) -> Self::Result
+	{
+		let service = match self.handlers.get_mut( &msg.type_id )
+		{
+			Some( service ) => service,
+			None            => return Err( EkkeIoError::NoHandlerForService( msg.service ) ),
+		};
+
+		if !service.members.iter().any( |m| m.actor == msg.actor )
+		{
+			let error = EkkeIoError::NotServiceProvider( msg.actor, msg.service );
+
+			warn!( self.log, "{}", &error );
+
+			return Err( error );
 		}
+
+		// Keep the service without members, so requests for it get ServiceUnavailable.
+		//
+		service.members.retain( |m| m.actor != msg.actor );
+
+		info!( self.log, "Rpc: actor {} no longer provides service: {}", &msg.actor, &msg.service );
+
+		#[ cfg( feature = "schema" ) ]
+		//
+		self.forget( &msg.type_id );
+
+		Ok(())
 	}
 }
 
 
 
+impl Handler<ServiceStopped> for Rpc
+{
+	type Result = ();
+
+	fn handle( &mut self, msg: ServiceStopped, _ctx: &mut Context<Self> ) -> Self::Result
+	{
+		// When the last member goes, the service stays without members, so requests for it get
+		// ServiceUnavailable until another actor registers.
+		//
+		if let Some( service ) = self.handlers.get_mut( &msg.type_id )
+		{
+			if let Some( pos ) = service.members.iter().position( |m| m.id == msg.id )
+			{
+				let member = service.members.remove( pos );
+
+				info!( self.log, "Rpc: actor {} for service {} has stopped", &member.actor, &service.name );
+			}
+		}
+
+		#[ cfg( feature = "schema" ) ]
+		//
+		self.forget( &msg.type_id );
+	}
+}
+
+
+
+impl Handler<GrantRole> for Rpc
+{
+	type Result = ();
+
+	fn handle( &mut self, msg: GrantRole, _ctx: &mut Context<Self> ) -> Self::Result
+	{
+		debug!( self.log, "Rpc: granting role {} to {:?}", &msg.role, &msg.identity );
+
+		self.roles.entry( msg.identity ).or_insert_with( HashSet::new ).insert( msg.role );
+	}
+}
+
+
+
+impl Handler<RevokeRole> for Rpc
+{
+	type Result = ();
+
+	fn handle( &mut self, msg: RevokeRole, _ctx: &mut Context<Self> ) -> Self::Result
+	{
+		debug!( self.log, "Rpc: revoking role {} from {:?}", &msg.role, &msg.identity );
+
+		if let Some( roles ) = self.roles.get_mut( &msg.identity )
+		{
+			roles.remove( &msg.role );
+		}
+	}
+}
+
+
+
+impl Handler<PeerClosed> for Rpc
+{
+	type Result = ();
+
+	fn handle( &mut self, msg: PeerClosed, _ctx: &mut Context<Self> ) -> Self::Result
+	{
+		if self.roles.remove( &Identity::Peer( msg.0 ) ).is_some()
+		{
+			debug!( self.log, "Rpc: dropped the roles of closed connection {:?}", msg.0 );
+		}
+
+		self.peer_buckets.remove( &msg.0 );
+	}
+}
+
+
+
+impl Handler<GetRpcStats> for Rpc
+{
+	type Result = RpcStats;
+
+	fn handle( &mut self, _msg: GetRpcStats, _ctx: &mut Context<Self> ) -> Self::Result
+	{
+		self.stats.clone()
+	}
+}
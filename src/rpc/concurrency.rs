@@ -0,0 +1,181 @@
+use crate :: { import::* };
+
+
+/// How many requests a service handles at the same time. Requests over `max_in_flight` wait in a queue
+/// of up to `queue` requests, after which callers get an `Overloaded` error. With a queue of 0, callers
+/// get the error as soon as max_in_flight requests are being handled.
+///
+#[ derive( Debug, Clone, Copy, PartialEq, Eq ) ]
+//
+pub struct ConcurrencyLimit
+{
+	pub max_in_flight: usize,
+	pub queue        : usize,
+}
+
+
+
+/// A request that is ready to be sent to the service actor.
+///
+pub(crate) type Job = Box< dyn FnOnce() + Send >;
+
+
+/// What happened to a request that was handed to Load::admit.
+///
+pub(crate) enum Admission
+{
+	/// The caller should run the job now.
+	///
+	Run( Job ),
+
+	/// The job will be run when a running request finishes.
+	///
+	Queued,
+
+	/// The queue is full.
+	///
+	Refused,
+}
+
+
+
+/// Book keeping of the requests running for one service.
+///
+pub(crate) struct Load
+{
+	limit    : ConcurrencyLimit     ,
+	in_flight: usize                ,
+	waiting  : VecDeque< Job >      ,
+}
+
+
+impl Load
+{
+	pub(crate) fn new( limit: ConcurrencyLimit ) -> Self
+	{
+		Self { limit, in_flight: 0, waiting: VecDeque::new() }
+	}
+
+
+	pub(crate) fn admit( &mut self, job: Job ) -> Admission
+	{
+		if self.in_flight < self.limit.max_in_flight
+		{
+			self.in_flight += 1;
+			return Admission::Run( job );
+		}
+
+		if self.waiting.len() < self.limit.queue
+		{
+			self.waiting.push_back( job );
+			return Admission::Queued;
+		}
+
+		Admission::Refused
+	}
+
+
+	/// A request finished, start the next one if there is one waiting.
+	///
+	pub(crate) fn done( load: &Arc<Mutex< Load >> )
+	{
+		// Don't hold the lock while running the job.
+		//
+		let next =
+		{
+			let mut load = load.lock();
+
+			match load.waiting.pop_front()
+			{
+				Some( job ) => Some( job ),
+
+				None =>
+				{
+					load.in_flight -= 1;
+					None
+				}
+			}
+		};
+
+		if let Some( job ) = next { job(); }
+	}
+}
+
+
+
+/// Holds a place in the Load of a service while a request runs. Dropping it calls `Load::done`, so the place is
+/// given back however the request ends, even if the task panics.
+///
+pub(crate) struct Slot( pub(crate) Arc<Mutex< Load >> );
+
+
+impl Drop for Slot
+{
+	fn drop( &mut self )
+	{
+		Load::done( &self.0 );
+	}
+}
+
+
+
+#[ cfg( test ) ]
+//
+mod tests
+{
+	use super::*;
+
+
+	fn job( ran: &Arc<AtomicUsize> ) -> Job
+	{
+		let ran = ran.clone();
+
+		Box::new( move || { ran.fetch_add( 1, Ordering::SeqCst ); } )
+	}
+
+
+	#[ test ]
+	//
+	fn admits_queues_and_refuses()
+	{
+		let ran  = Arc::new( AtomicUsize::new( 0 ) );
+		let load = Arc::new( Mutex::new( Load::new( ConcurrencyLimit { max_in_flight: 1, queue: 1 } ) ) );
+
+		match load.lock().admit( job( &ran ) ) { Admission::Run( _ ) => {}, _ => panic!( "expected Run"     ) }
+		match load.lock().admit( job( &ran ) ) { Admission::Queued   => {}, _ => panic!( "expected Queued"  ) }
+		match load.lock().admit( job( &ran ) ) { Admission::Refused  => {}, _ => panic!( "expected Refused" ) }
+
+		// The first one finishes, the queued one runs.
+		//
+		drop( Slot( load.clone() ) );
+
+		assert_eq!( ran.load( Ordering::SeqCst ), 1 );
+		assert_eq!( load.lock().in_flight       , 1 );
+
+		// The second one finishes, nothing is waiting.
+		//
+		drop( Slot( load.clone() ) );
+
+		assert_eq!( load.lock().in_flight, 0 );
+	}
+
+
+	#[ test ]
+	//
+	fn slot_is_released_on_panic()
+	{
+		let load = Arc::new( Mutex::new( Load::new( ConcurrencyLimit { max_in_flight: 1, queue: 0 } ) ) );
+
+		match load.lock().admit( Box::new( || {} ) ) { Admission::Run( _ ) => {}, _ => panic!( "expected Run" ) }
+
+		let slot   = Slot( load.clone() );
+		let result = std::panic::catch_unwind( std::panic::AssertUnwindSafe( move ||
+		{
+			let _slot = slot;
+			panic!( "the service blew up" );
+		}));
+
+		assert!( result.is_err() );
+		assert_eq!( load.lock().in_flight, 0 );
+	}
+}
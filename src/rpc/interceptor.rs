@@ -0,0 +1,53 @@
+use crate :: { import::*, IpcMessage, IpcResponse, EkkeIoError };
+
+
+/// Hooks Rpc runs for every request that passes through it. Use this for things every service needs,
+/// like logging, metrics, authentication or validation, rather than doing it in each service actor.
+///
+/// Interceptors are added with `Rpc::with_interceptor`. The `on_request_*` hooks run in the order the
+/// interceptors were added, the `on_response_*` hooks in reverse order, so the first interceptor wraps
+/// all the others.
+///
+/// All methods have a default implementation that does nothing, so only implement what you need.
+///
+///     struct Audit { log: Logger }
+///
+///     impl Interceptor for Audit
+///     {
+///         fn on_request_in( &self, msg: &mut IpcMessage ) -> Result< (), EkkeIoError >
+///         {
+///             info!( self.log, "call to {} from {:?}", &msg.service, &msg.peer );
+///             Ok(())
+///         }
+///     }
+///
+///     let rpc = Rpc::new( log.clone(), crate::service_map ).with_interceptor( Audit{ log } ).start();
+///
+pub trait Interceptor: Send + Sync
+{
+	/// An incoming request, before it's dispatched to the service. Returning an error refuses the
+	/// request, and the error is sent back to the caller.
+	///
+	fn on_request_in( &self, _msg: &mut IpcMessage ) -> Result< (), EkkeIoError > { Ok(()) }
+
+	/// The response of a service to an incoming request, before it's sent back. Returning an error
+	/// sends the error to the caller instead of the response.
+	///
+	fn on_response_out( &self, _msg: &mut IpcMessage ) -> Result< (), EkkeIoError > { Ok(()) }
+
+	/// A request we send out, before it goes to the peer. Returning an error fails the request
+	/// without sending it.
+	///
+	fn on_request_out( &self, _msg: &mut IpcMessage ) -> Result< (), EkkeIoError > { Ok(()) }
+
+	/// The outcome of a request we sent out, before it is returned to the caller. You can change it
+	/// in place, eg. turn a response into an error.
+	///
+	fn on_response_in( &self, _result: &mut Result< IpcResponse, EkkeIoError > ) {}
+}
+
+
+
+/// The interceptors of an Rpc in order. Requests in flight keep the chain they started with.
+///
+pub(crate) type Chain = Arc< Vec< Arc< dyn Interceptor > > >;
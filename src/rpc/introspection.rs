@@ -0,0 +1,84 @@
+use crate :: { import::*, Rpc, IpcMessage, IpcRequestOut, MessageType, ConnID, EkkeIoError };
+
+
+/// The name of the service every Rpc answers itself with a list of the services it provides. Requests
+/// for it never reach your matcher.
+///
+pub const LIST_SERVICES: &str = "ekke_io::ListServices";
+
+
+
+/// Describes a service registered with an Rpc, see [`Rpc::list_services`](struct.Rpc.html#method.list_services).
+///
+#[ derive( Debug, Clone, PartialEq, Eq, Serialize, Deserialize ) ]
+//
+pub struct ServiceInfo
+{
+	/// The name requests use. This is the type name of the request message, unless the service was
+	/// registered as a version of a service.
+	///
+	pub name: String,
+
+	pub version: u32,
+
+	/// The type name of the request message.
+	///
+	#[ serde( default ) ]
+	//
+	pub request_type: String,
+
+	/// The type name of the response payload, if the service told Rpc when registering.
+	///
+	#[ serde( default ) ]
+	//
+	pub response_type: Option< String >,
+
+	/// Type names of the actors that provide the service.
+	///
+	pub actors: Vec<String>,
+}
+
+
+
+impl Rpc
+{
+	/// Ask the Rpc on the other side of a connection which services it provides.
+	///
+	///     let services = await!( Rpc::list_services( rpc.clone(), ekke_server.recipient() ) )?;
+	///
+	///     let can_register = services.iter().any( |s| s.name == "RegisterApplication" );
+	///
+	pub async fn list_services( rpc: Addr<Rpc>, ipc_peer: Recipient< IpcMessage > ) -> Result< Vec<ServiceInfo>, EkkeIoError >
+	{
+		let ipc_msg = IpcMessage::new( LIST_SERVICES.to_string(), (), MessageType::IpcRequestOut, ConnID::new() );
+
+		let response = awaits!( rpc.send( IpcRequestOut { ipc_peer, ipc_msg, resolve: None } ) )
+
+			.map_err( |e| EkkeIoError::ActixMailboxError( "Rpc".into(), e ) )??
+		;
+
+		des( &response.ipc_msg.payload ).map_err( |e| EkkeIoError::InvalidResponse( e.to_string() ) )
+	}
+
+
+	/// Answer a request for LIST_SERVICES.
+	///
+	pub(crate) fn introspect( &self, msg: IpcMessage, ipc_peer: Recipient< IpcMessage > )
+	{
+		let mut services: Vec<ServiceInfo> = self.handlers.values().filter( |s| !s.members.is_empty() ).map( |service| ServiceInfo
+		{
+			name   : service.name.clone()                                     ,
+			version: service.version                                          ,
+
+			request_type : service.request_type .clone(),
+			response_type: service.response_type.clone(),
+
+			actors : service.members.iter().map( |m| m.actor.clone() ).collect(),
+
+		}).collect();
+
+		services.sort_by( |a, b| ( &a.name, a.version ).cmp( &( &b.name, b.version ) ) );
+
+		let _ = ipc_peer.do_send( IpcMessage::new( msg.service, services, MessageType::Response, msg.conn_id ) );
+	}
+}
@@ -0,0 +1,165 @@
+use crate :: { import::*, ConnID, PeerInfo };
+
+
+/// Who is allowed to call a service. Rpc checks the policy of a service before it even deserializes
+/// the request, and answers with `EkkeIoError::PermissionDenied` if the caller isn't allowed.
+///
+/// Policies that look at credentials only allow callers that have them, currently that means peers
+/// connected over a unix domain socket on linux or android.
+///
+#[ derive( Clone ) ]
+//
+pub enum Policy
+{
+	/// Anybody that can connect may call this service. This is the default.
+	///
+	Public,
+
+	/// Only processes running as one of these users.
+	///
+	Uids( Vec<u32> ),
+
+	/// Only these processes, eg. the one you just spawned.
+	///
+	Pids( Vec<i32> ),
+
+	/// Only callers that have been granted one of these roles with [`GrantRole`](struct.GrantRole.html).
+	///
+	Roles( Vec<String> ),
+
+	/// Decide for yourself.
+	///
+	Custom( Arc< dyn Fn( &RequestContext ) -> bool + Send + Sync > ),
+}
+
+
+impl Default for Policy
+{
+	fn default() -> Self { Policy::Public }
+}
+
+
+impl fmt::Debug for Policy
+{
+	fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result
+	{
+		match self
+		{
+			Policy::Public          => write!( f, "Policy::Public"            ),
+			Policy::Uids  ( uids  ) => write!( f, "Policy::Uids({:?})" , uids  ),
+			Policy::Pids  ( pids  ) => write!( f, "Policy::Pids({:?})" , pids  ),
+			Policy::Roles ( roles ) => write!( f, "Policy::Roles({:?})", roles ),
+			Policy::Custom( _     ) => write!( f, "Policy::Custom"            ),
+		}
+	}
+}
+
+
+impl Policy
+{
+	/// Whether the caller described by the context may call the service.
+	///
+	pub fn allows( &self, context: &RequestContext ) -> bool
+	{
+		let creds = context.peer.and_then( |p| p.credentials );
+
+		match self
+		{
+			Policy::Public          => true,
+			Policy::Uids  ( uids  ) => creds.map( |c| uids.contains( &c.uid ) ).unwrap_or( false ),
+			Policy::Pids  ( pids  ) => creds.map( |c| pids.contains( &c.pid ) ).unwrap_or( false ),
+			Policy::Roles ( roles ) => roles.iter().any( |r| context.roles.contains( r ) ),
+			Policy::Custom( check ) => check( context ),
+		}
+	}
+}
+
+
+
+/// Everything Rpc knows about an incoming request before deserializing it. This is what policies
+/// get to decide on.
+///
+#[ derive( Debug ) ]
+//
+pub struct RequestContext<'a>
+{
+	/// The service that is being called.
+	///
+	pub service: &'a str,
+
+	/// The connection the request came in on. None if IpcPeer didn't tell us.
+	///
+	pub peer: Option< &'a PeerInfo >,
+
+	/// The roles granted to the caller, both to the connection and to it's uid.
+	///
+	pub roles: &'a [String],
+}
+
+
+
+/// Something roles can be granted to.
+///
+#[ derive( Debug, Clone, Copy, PartialEq, Eq, Hash ) ]
+//
+pub enum Identity
+{
+	/// A single connection, see [`PeerInfo::id`](struct.PeerInfo.html#structfield.id). Useful when a peer
+	/// authenticated itself over the connection, eg. a browser that logged in.
+	///
+	Peer( ConnID ),
+
+	/// Every process running as this user.
+	///
+	Uid( u32 ),
+}
+
+
+impl Identity
+{
+	/// The identities that apply to a caller.
+	///
+	pub(crate) fn of( peer: &PeerInfo ) -> Vec<Identity>
+	{
+		let mut ids = vec![ Identity::Peer( peer.id ) ];
+
+		if let Some( creds ) = peer.credentials
+		{
+			ids.push( Identity::Uid( creds.uid ) );
+		}
+
+		ids
+	}
+}
+
+
+
+/// Give an identity a role, so it can call services with a `Policy::Roles` that contains this role.
+///
+#[ derive( Debug, Clone, Message ) ]
+//
+pub struct GrantRole
+{
+	pub identity: Identity,
+	pub role    : String  ,
+}
+
+
+/// Take a role away again.
+///
+#[ derive( Debug, Clone, Message ) ]
+//
+pub struct RevokeRole
+{
+	pub identity: Identity,
+	pub role    : String  ,
+}
+
+
+
+/// Sent by IpcPeer when it's connection is gone, so Rpc can forget the roles granted to it. The same
+/// connection can never come back.
+///
+#[ derive( Debug, Clone, Copy, Message ) ]
+//
+pub(crate) struct PeerClosed( pub(crate) ConnID );
@@ -0,0 +1,156 @@
+use crate :: { import::* };
+
+
+/// A token bucket: requests are allowed at `per_second` on average, with bursts of up to `burst` requests.
+///
+#[ derive( Debug, Clone, Copy, PartialEq ) ]
+//
+pub struct RateLimit
+{
+	pub per_second: f64,
+	pub burst     : u32,
+}
+
+
+
+/// The state of one token bucket.
+///
+#[ derive( Debug ) ]
+//
+pub(crate) struct TokenBucket
+{
+	limit : RateLimit,
+	tokens: f64      ,
+	last  : Instant  ,
+}
+
+
+impl TokenBucket
+{
+	pub(crate) fn new( limit: RateLimit ) -> Self
+	{
+		Self { limit, tokens: f64::from( limit.burst ), last: Instant::now() }
+	}
+
+
+	/// Take a token if there is one.
+	///
+	pub(crate) fn take( &mut self ) -> bool
+	{
+		if self.has_token()
+		{
+			self.tokens -= 1.0;
+			return true;
+		}
+
+		false
+	}
+
+
+	/// Whether a token could be taken, without taking it.
+	///
+	pub(crate) fn has_token( &mut self ) -> bool
+	{
+		self.refill();
+
+		self.tokens >= 1.0
+	}
+
+
+	/// A full bucket is the same as no bucket, so it can be forgotten.
+	///
+	pub(crate) fn is_full( &mut self ) -> bool
+	{
+		self.refill();
+
+		self.tokens >= f64::from( self.limit.burst )
+	}
+
+
+	fn refill( &mut self )
+	{
+		let now     = Instant::now();
+		let elapsed = now.duration_since( self.last );
+		let secs    = elapsed.as_secs() as f64 + f64::from( elapsed.subsec_nanos() ) / 1e9;
+
+		self.tokens = ( self.tokens + secs * self.limit.per_second ).min( f64::from( self.limit.burst ) );
+		self.last   = now;
+	}
+}
+
+
+
+/// Counters Rpc keeps for monitoring. Get them with [`GetRpcStats`](struct.GetRpcStats.html).
+///
+#[ derive( Debug, Clone, Default, MessageResponse ) ]
+//
+pub struct RpcStats
+{
+	/// Incoming requests, including the ones that were refused.
+	///
+	pub requests: u64,
+
+	/// Requests refused because the peer went over it's rate limit.
+	///
+	pub rate_limited_peer: u64,
+
+	/// Requests refused because the service went over it's rate limit, per service.
+	///
+	pub rate_limited_service: HashMap< String, u64 >,
+}
+
+
+/// Ask Rpc for it's counters.
+///
+#[ derive( Debug, Message ) ] #[ rtype( result="RpcStats" ) ]
+//
+pub struct GetRpcStats;
+
+
+
+#[ cfg( test ) ]
+//
+mod tests
+{
+	use super::*;
+
+
+	#[ test ]
+	//
+	fn allows_a_burst()
+	{
+		let mut bucket = TokenBucket::new( RateLimit { per_second: 0.001, burst: 3 } );
+
+		assert!(  bucket.take() );
+		assert!(  bucket.take() );
+		assert!(  bucket.take() );
+		assert!( !bucket.take() );
+	}
+
+
+	#[ test ]
+	//
+	fn looking_is_free()
+	{
+		let mut bucket = TokenBucket::new( RateLimit { per_second: 0.001, burst: 1 } );
+
+		assert!(  bucket.has_token() );
+		assert!(  bucket.has_token() );
+		assert!(  bucket.take()      );
+		assert!( !bucket.has_token() );
+	}
+
+
+	#[ test ]
+	//
+	fn refills()
+	{
+		let mut bucket = TokenBucket::new( RateLimit { per_second: 1000.0, burst: 1 } );
+
+		assert!( bucket.take() );
+
+		std::thread::sleep( Duration::from_millis( 10 ) );
+
+		assert!( bucket.take() );
+	}
+}
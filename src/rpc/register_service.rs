@@ -1,14 +1,20 @@
 use crate :: { import::*      };
-use crate ::{ Rpc, IpcMessage };
+use crate ::{ Rpc, IpcMessage, EkkeIoError, rpc::{ Policy, Balance } };
 
 
 
 /// The message type for registering your services with the Rpc component. After providing
 /// the callback function to Rpc which allows it to know the types to deserialize incoming
 /// messages to, and registering, the rpc component will automatically forward incoming messages
-/// to your actor.
+/// to your actor. The policy decides who may call the service.
 ///
-#[ derive( Message ) ]
+/// Registering a service that already has an actor fails with `EkkeIoError::DoubleServiceRegistration`,
+/// unless replace is set, in which case the new actor takes over.
+///
+/// If pool is set, more actors can provide the service as long as they all set pool. Rpc spreads the
+/// requests over them as the Balance of the first one says. Replace takes over the whole pool.
+///
+#[ derive( Message ) ] #[ rtype( result="Result<(), EkkeIoError>" ) ]
 //
 pub struct RegisterService<M>
 
@@ -18,7 +24,130 @@ where
 	pub service  : String,
 	pub actor    : String,
 	pub type_id  : TypeId,
-	pub recipient: Recipient<M>
+	pub recipient: Recipient<M>,
+	pub policy   : Policy,
+	pub replace  : bool  ,
+	pub pool     : Option< Balance >,
+
+	/// Services that don't care about versions are version 1.
+	///
+	pub version  : u32   ,
+
+	/// The type name of the request message, M.
+	///
+	pub request_type : String,
+
+	/// The type name of the payload the service answers with, if you want peers to know it. Services
+	/// answer with an IpcMessage, so Rpc can't find it by itself. See `responds_with`.
+	///
+	pub response_type: Option< String >,
+}
+
+
+
+impl<M> RegisterService<M>
+
+where
+	M: Message<Result = IpcMessage> + TypeName + Send + 'static
+{
+	/// Register any recipient for M as the service, eg. actors on a `SyncArbiter`, which can't use
+	/// [`RegisterServiceMethod`](trait.RegisterServiceMethod.html) as they don't have an AsyncContext.
+	/// Set pool on the result to spread requests over several sync arbiters.
+	///
+	///     let hasher = SyncArbiter::start( 4, || Hasher );
+	///
+	///     rpc.do_send( RegisterService::of::<Hasher>( hasher.recipient::<Hash>(), Policy::Public ) );
+	///
+	pub fn of<A: TypeName>( recipient: Recipient<M>, policy: Policy ) -> Self
+	{
+		Self
+		{
+			service  : M::type_name(),
+			actor    : A::type_name(),
+			type_id  : TypeId::of::<M>(),
+			recipient,
+			policy   ,
+			replace  : false,
+			pool     : None ,
+			version  : 1    ,
+
+			request_type : M::type_name(),
+			response_type: None          ,
+		}
+	}
+
+
+	/// Tell peers that list the services which type the service answers with.
+	///
+	///     rpc.do_send( RegisterService::of::<Hasher>( hasher.recipient::<Hash>(), Policy::Public ).responds_with::<Digest>() );
+	///
+	pub fn responds_with<R: TypeName>( mut self ) -> Self
+	{
+		self.response_type = Some( R::type_name() );
+		self
+	}
+
+
+	/// Take over the service if another actor already provides it.
+	///
+	pub fn replacing( mut self ) -> Self
+	{
+		self.replace = true;
+		self
+	}
+
+
+	/// Provide the service together with other actors of the same type, see
+	/// [`join_service_pool`](trait.RegisterServiceMethod.html#method.join_service_pool).
+	///
+	pub fn in_pool( mut self, balance: Balance ) -> Self
+	{
+		self.pool = Some( balance );
+		self
+	}
+
+
+	/// Provide a version of the service called name, see
+	/// [`register_service_version`](trait.RegisterServiceMethod.html#method.register_service_version).
+	///
+	pub fn as_version( mut self, name: &str, version: u32 ) -> Self
+	{
+		self.service = name.to_string();
+		self.version = version;
+		self
+	}
+}
+
+
+
+/// Stop forwarding requests for a service to the actor that registered it. Only the actor type that
+/// provides the service can unregister it, if it joined a pool, all actors of that type leave the pool.
+/// Fails with `EkkeIoError::NoHandlerForService` if the service wasn't registered and with
+/// `EkkeIoError::NotServiceProvider` if actor doesn't provide it.
+///
+/// Afterwards, and when a service actor stops, Rpc answers requests for the service with
+/// `ServiceUnavailable` until another actor registers. You don't have to unregister when a service
+/// actor stops, Rpc notices that by itself.
+///
+#[ derive( Debug, Message ) ] #[ rtype( result="Result<(), EkkeIoError>" ) ]
+//
+pub struct UnregisterService
+{
+	pub service: String,
+	pub type_id: TypeId,
+
+	/// The type name of the actor that registered the service.
+	///
+	pub actor  : String,
+}
+
+
+impl UnregisterService
+{
+	pub fn of<M: TypeName + 'static, A: TypeName>() -> Self
+	{
+		Self { service: M::type_name(), type_id: TypeId::of::<M>(), actor: A::type_name() }
+	}
 }
 
 
@@ -26,8 +155,11 @@ where
 /// This trait creates a convenient way for your Service Actors to register themselves
 /// with the Rpc component. The registering is needed so that the Rpc component would
 /// know to which actor to send an incoming message. The choice is based on the type of
-/// the message. Hence there can only be one actor for each service, but an actor can
-/// provide multiple services.
+/// the message. Normally there can only be one actor for each service, but you can spread
+/// the work over several actors with `join_service_pool`. An actor can provide multiple services.
+///
+/// The methods return the request to Rpc, so you can find out whether registering worked.
+/// The registration is sent right away, so you can also just drop it if you don't care.
 ///
 /// Currently this requires that your actor and your message types
 /// derive [`Typename`](https://docs.rs/typename/0.1.0/typename/trait.TypeName.html)
@@ -63,14 +195,19 @@ where
 ///     		//
 ///     		let rpc = Rpc::new( self.log.new( o!( "Actor" => "Rpc" ) ), crate::service_map ).start();
 ///
-///     		// Tell rpc that we provide the service for requests of type RegisterApplication
-///     		// This method would be even more useful if you wouldn't have to pass a reference to rpc
-///     		// but if rpc would become an actix service who's address can be found in the registry,
-///     		// that would make it harder for users to replace the rpc component if wanted.
+///     		// Tell rpc that we provide the service for requests of type RegisterApplication.
+///     		// If you run Rpc as a SystemService, use register_system_service instead and you
+///     		// don't need the address.
 ///     		//
 ///     		// The main reason for this trait is to reduce boilerplate in your actors.
 ///     		//
-///     		self.register_service::<RegisterApplication>( &rpc, ctx );
+///     		let registered = self.register_service::<RegisterApplication>( &rpc, ctx );
+///
+///     		ctx.spawn( registered.into_actor( self ).map( |result, actor, _ctx|
+///     		{
+///     			if let Err( e ) = result { error!( actor.log, "{}", e ); }
+///
+///     		}).map_err( |_, _, _| () ));
 ///
 ///     		//...
 ///     	}
@@ -85,7 +222,97 @@ pub trait RegisterServiceMethod
 
 	/// See trait documentation for docs
 	///
-	fn register_service<M>( &self, rpc: &Addr< Rpc >, ctx: &mut Self::Context )
+	fn register_service<M>( &self, rpc: &Addr< Rpc >, ctx: &mut Self::Context ) -> Request< Rpc, RegisterService<M> >
+
+	where
+
+		  Self                     : Handler<M, Result = IpcMessage>
+		, M                        : Message<   Result = IpcMessage> + Message + TypeName + Send + 'static
+		, <Self as Actor>::Context : ToEnvelope<Self, M>
+	{
+		self.register_service_with_policy::<M>( rpc, Policy::Public, ctx )
+	}
+
+
+	/// Register with the Rpc from the actix registry, see [`ConfigureRpc`](struct.ConfigureRpc.html). The other
+	/// methods work with it too if you pass them `&Rpc::from_registry()`.
+	///
+	///     self.register_system_service::<RegisterApplication>( ctx );
+	///
+	fn register_system_service<M>( &self, ctx: &mut Self::Context ) -> Request< Rpc, RegisterService<M> >
+
+	where
+
+		  Self                     : Handler<M, Result = IpcMessage>
+		, M                        : Message<   Result = IpcMessage> + Message + TypeName + Send + 'static
+		, <Self as Actor>::Context : ToEnvelope<Self, M>
+	{
+		self.register_service::<M>( &Rpc::from_registry(), ctx )
+	}
+
+
+	/// Like register_service, but only callers allowed by policy can use the service.
+	///
+	///     self.register_service_with_policy::<RegisterApplication>( &rpc, Policy::Pids( vec![ child.id() as i32 ] ), ctx );
+	///
+	fn register_service_with_policy<M>( &self, rpc: &Addr< Rpc >, policy: Policy, ctx: &mut Self::Context ) -> Request< Rpc, RegisterService<M> >
+
+	where
+
+		  Self                     : Handler<M, Result = IpcMessage>
+		, M                        : Message<   Result = IpcMessage> + Message + TypeName + Send + 'static
+		, <Self as Actor>::Context : ToEnvelope<Self, M>
+	{
+		rpc.send( RegisterService::of::<Self>( ctx.address().recipient::<M>(), policy ) )
+	}
+
+
+	/// Provide a service together with other actors of the same type, eg. started with
+	/// `Arbiter::start` on several threads, so a busy service can use more than one core.
+	/// Every actor in the pool calls this.
+	///
+	///     for _ in 0..num_cpus::get()
+	///     {
+	///     	Arbiter::start( |ctx: &mut Context<Hasher>|
+	///     	{
+	///     		let hasher = Hasher::new();
+	///     		hasher.join_service_pool::<Hash>( &rpc, Policy::Public, Balance::LeastLoaded, ctx );
+	///     		hasher
+	///     	});
+	///     }
+	///
+	fn join_service_pool<M>( &self, rpc: &Addr< Rpc >, policy: Policy, balance: Balance, ctx: &mut Self::Context ) -> Request< Rpc, RegisterService<M> >
+
+	where
+
+		  Self                     : Handler<M, Result = IpcMessage>
+		, M                        : Message<   Result = IpcMessage> + Message + TypeName + Send + 'static
+		, <Self as Actor>::Context : ToEnvelope<Self, M>
+	{
+		rpc.send( RegisterService::of::<Self>( ctx.address().recipient::<M>(), policy ).in_pool( balance ) )
+	}
+
+
+	/// Like register_service_with_policy, but takes over the service if another actor already provides it.
+	///
+	fn replace_service<M>( &self, rpc: &Addr< Rpc >, policy: Policy, ctx: &mut Self::Context ) -> Request< Rpc, RegisterService<M> >
+
+	where
+
+		  Self                     : Handler<M, Result = IpcMessage>
+		, M                        : Message<   Result = IpcMessage> + Message + TypeName + Send + 'static
+		, <Self as Actor>::Context : ToEnvelope<Self, M>
+	{
+		rpc.send( RegisterService::of::<Self>( ctx.address().recipient::<M>(), policy ).replacing() )
+	}
+
+
+	/// Provide a version of a service. Versions of the same service use different message types, so name is
+	/// the service name they share on the wire. Your matcher should use `Rpc::deser_versioned` for it.
+	///
+	///     self.register_service_version::<RegisterApplicationV2>( &rpc, "RegisterApplication", 2, Policy::Public, ctx );
+	///
+	fn register_service_version<M>( &self, rpc: &Addr< Rpc >, name: &str, version: u32, policy: Policy, ctx: &mut Self::Context ) -> Request< Rpc, RegisterService<M> >
 
 	where
 
@@ -93,18 +320,19 @@ pub trait RegisterServiceMethod
 		, M                        : Message<   Result = IpcMessage> + Message + TypeName + Send + 'static
 		, <Self as Actor>::Context : ToEnvelope<Self, M>
 	{
-		// We use do_send, because it doesn't need to be async
-		//
-		rpc.do_send
-		(
-			RegisterService
-			{
-				service  : M::type_name(),
-				actor    : Self::type_name(),
-				type_id  : TypeId::of::<M>(),
-				recipient: ctx.address().recipient::<M>()
-			}
-		)
+		rpc.send( RegisterService::of::<Self>( ctx.address().recipient::<M>(), policy ).as_version( name, version ) )
+	}
+
+
+	/// Stop providing a service, see [`UnregisterService`](struct.UnregisterService.html).
+	///
+	fn unregister_service<M>( &self, rpc: &Addr< Rpc > ) -> Request< Rpc, UnregisterService >
+
+	where
+
+		M: TypeName + 'static
+	{
+		rpc.send( UnregisterService::of::<M, Self>() )
 	}
 }
 
@@ -0,0 +1,493 @@
+//! Retrying outgoing requests to idempotent services, and making sure a request for a service that
+//! isn't idempotent is only handled once, even if the caller sends it again.
+//
+use crate :: { import::*, Rpc, IpcMessage, IpcRequestOut, IpcResponse, EkkeIoError, ErrorCode, ConnID, RemoteError, MessageType, rpc::{ Pending, Identity } };
+
+use tokio :: { timer::{ Delay, Timeout } };
+
+
+/// How to retry requests for a service that can safely be handled more than once. Set it with
+/// `Rpc::with_retry`. Only errors that might go away by themselves are retried: the connection going away,
+/// a full queue, overloaded or rate limited services, services that are temporarily unavailable and attempts
+/// that timed out.
+///
+/// Every attempt uses the same conn_id, so the other side can recognize it, see `Rpc::with_at_most_once`.
+///
+/// Retries go to the recipient the `resolve` of the request returns, or to the same `ipc_peer` if there
+/// is none. That way they can go to the new connection after a reconnect.
+///
+#[ derive( Debug, Clone, Copy, PartialEq ) ]
+//
+pub struct RetryPolicy
+{
+	/// Including the first one.
+	///
+	pub max_attempts: u32,
+
+	/// How long to wait before the first retry.
+	///
+	pub backoff: Duration,
+
+	/// The wait gets multiplied by this for every next retry.
+	///
+	pub multiplier: f64,
+
+	/// But it never gets longer than this.
+	///
+	pub max_backoff: Duration,
+
+	/// How long to wait for the response to a single attempt. None waits as long as it takes.
+	///
+	pub timeout: Option< Duration >,
+}
+
+
+impl Default for RetryPolicy
+{
+	fn default() -> Self
+	{
+		Self
+		{
+			max_attempts: 5                           ,
+			backoff     : Duration::from_millis( 100 ),
+			multiplier  : 2.0                         ,
+			max_backoff : Duration::from_secs  ( 10  ),
+			timeout     : Some( Duration::from_secs( 30 ) ),
+		}
+	}
+}
+
+
+impl RetryPolicy
+{
+	/// How long to wait after the given failed attempt, counting from 1.
+	///
+	pub fn backoff( &self, attempt: u32 ) -> Duration
+	{
+		let factor = self.multiplier.powi( attempt.saturating_sub( 1 ) as i32 );
+		let millis = ( self.backoff.as_millis() as f64 * factor ).min( self.max_backoff.as_millis() as f64 );
+
+		Duration::from_millis( millis as u64 )
+	}
+
+
+	/// Whether an error might go away if we try again.
+	///
+	pub fn is_transient( error: &EkkeIoError ) -> bool
+	{
+		match error
+		{
+			EkkeIoError::ConnectionClosed        |
+			EkkeIoError::QueueFull               |
+			EkkeIoError::Timeout           ( _ ) |
+			EkkeIoError::Overloaded        ( _ ) |
+			EkkeIoError::RateLimited       ( _ ) |
+			EkkeIoError::ServiceUnavailable( _ ) => true,
+
+			EkkeIoError::IpcError( remote ) => match remote.code
+			{
+				ErrorCode::Unavailable |
+				ErrorCode::Overloaded  |
+				ErrorCode::RateLimited => true ,
+				_                      => false,
+			},
+
+			_ => false,
+		}
+	}
+}
+
+
+
+/// A single attempt of a request that is being retried.
+///
+#[ derive( Message ) ] #[ rtype( result="Result<IpcResponse, EkkeIoError>" ) ]
+//
+pub(crate) struct Attempt( pub(crate) IpcRequestOut );
+
+
+impl Handler<Attempt> for Rpc
+{
+	type Result = ActixFuture< Result<IpcResponse, EkkeIoError> >;
+
+	fn handle( &mut self, msg: Attempt, _ctx: &mut Context<Self> ) -> Self::Result
+	{
+		ActixFuture::from( self.request( msg.0 ) )
+	}
+}
+
+
+
+impl Rpc
+{
+	/// Send a request and keep retrying it according to policy.
+	///
+	pub(crate) fn request_with_retry( &self, mut msg: IpcRequestOut, policy: RetryPolicy )
+
+		-> impl StdFuture< Output = Result<IpcResponse, EkkeIoError> >
+	{
+		let first     = self.request( msg.clone() );
+		let addr      = self.addr.clone();
+		let log       = self.log.clone();
+		let responses = self.responses.clone();
+
+		async move
+		{
+			let mut result  = await!( Self::within( first, &msg, &policy, &responses ) );
+			let mut attempt = 1;
+
+			while attempt < policy.max_attempts
+			{
+				let wait = match &result
+				{
+					Err( e ) if RetryPolicy::is_transient( e ) => policy.backoff( attempt ),
+					_                                           => break,
+				};
+
+				// We can't do without our address, but it's set as soon as Rpc starts.
+				//
+				let addr = match &addr
+				{
+					Some( addr ) => addr,
+					None         => break,
+				};
+
+				debug!( log, "Rpc: retrying request for service {} in {:?}", &msg.ipc_msg.service, wait );
+
+				if awaits!( Delay::new( Instant::now() + wait ) ).is_err() { break; }
+
+				// The connection might have been replaced in the meantime.
+				//
+				if let Some( peer ) = msg.resolve.as_ref().and_then( |resolve| resolve() )
+				{
+					msg.ipc_peer = peer;
+				}
+
+				let next = addr.send( Attempt( msg.clone() ) );
+
+				let next: Pending = Box::pin( async move
+				{
+					awaits!( next ).unwrap_or_else( |e| Err( EkkeIoError::ActixMailboxError( "Rpc".into(), e ) ) )
+				});
+
+				result   = await!( Self::within( next, &msg, &policy, &responses ) );
+				attempt += 1;
+			}
+
+			result
+		}
+	}
+
+
+	/// Give up on an attempt after the timeout of the policy. We stop expecting the response then, a late one
+	/// is ignored.
+	///
+	fn within( attempt: Pending, msg: &IpcRequestOut, policy: &RetryPolicy, responses: &Responses ) -> Pending
+	{
+		let timeout = match policy.timeout
+		{
+			Some( timeout ) => timeout ,
+			None            => return attempt,
+		};
+
+		let service   = msg.ipc_msg.service.clone();
+		let conn_id   = msg.ipc_msg.conn_id;
+		let responses = responses.clone();
+
+		Box::pin( async move
+		{
+			awaits!( Timeout::new( attempt.compat(), timeout ) ).map_err( |e|
+			{
+				// Timer errors only happen when the runtime shuts down, we won't get a response either.
+				//
+				e.into_inner().unwrap_or_else( ||
+				{
+					responses.lock().remove( &conn_id );
+
+					EkkeIoError::Timeout( service )
+				})
+			})
+		})
+	}
+}
+
+
+
+/// What we know about a request for a service that should be handled at most once.
+///
+pub(crate) struct Seen
+{
+	/// When we can forget about it. Peers that are still waiting for the response keep it around longer.
+	///
+	pub(crate) expires: Instant,
+
+	/// The response, once the service answered.
+	///
+	pub(crate) response: Option< IpcMessage >,
+
+	/// Peers that sent the request again while the service was still working on it.
+	///
+	pub(crate) waiting: Vec< Recipient< IpcMessage > >,
+}
+
+
+impl Seen
+{
+	/// Whether to keep it around. Until the service answered, we keep it for as long as someone is waiting.
+	///
+	pub(crate) fn keep( &self, now: Instant ) -> bool
+	{
+		self.expires > now || ( self.response.is_none() && !self.waiting.is_empty() )
+	}
+}
+
+
+/// Who sent a request. The conn_id is chosen by the caller, so two callers might use the same one. We tell them
+/// apart by user if we know it, which also recognizes a retry over a new connection, otherwise by connection.
+///
+pub(crate) type SeenKey = ( Option< Identity >, ConnID );
+
+
+pub(crate) fn seen_key( msg: &IpcMessage ) -> SeenKey
+{
+	let caller = msg.peer.as_ref().map( |peer| match peer.credentials
+	{
+		Some( creds ) => Identity::Uid ( creds.uid ),
+		None          => Identity::Peer( peer.id   ),
+	});
+
+	( caller, msg.conn_id )
+}
+
+
+/// The requests for services that should be handled at most once.
+///
+pub(crate) type SeenMap = Arc<Mutex< HashMap< SeenKey, Seen > >>;
+
+
+/// The senders for the responses we expect, by conn_id.
+///
+pub(crate) type Responses = Arc<Mutex< HashMap< ConnID, channel::oneshot::Sender< Result<IpcResponse, EkkeIoError> > > >>;
+
+
+/// A service that should be handled at most once answered a request. Give the response to everyone who
+/// asked again in the meantime and keep it for those that ask later. Errors that might go away by themselves
+/// aren't kept, the request wasn't handled, so the caller may try again.
+///
+pub(crate) fn answered( seen: &SeenMap, key: SeenKey, response: &IpcMessage )
+{
+	let mut map = seen.lock();
+
+	let forget = match map.get_mut( &key )
+	{
+		Some( seen ) =>
+		{
+			for peer in seen.waiting.drain( .. )
+			{
+				let _ = peer.do_send( response.clone() );
+			}
+
+			if transient( response ) { true }
+
+			else
+			{
+				seen.response = Some( response.clone() );
+				false
+			}
+		}
+
+		None => false,
+	};
+
+	if forget { map.remove( &key ); }
+}
+
+
+/// Whether a response is an error that might go away if the request is sent again.
+///
+fn transient( response: &IpcMessage ) -> bool
+{
+	if response.ms_type != MessageType::Error { return false; }
+
+	match des::<RemoteError>( &response.payload )
+	{
+		Ok ( remote ) => RetryPolicy::is_transient( &EkkeIoError::IpcError( remote ) ),
+		Err( _      ) => false,
+	}
+}
+
+
+
+impl Rpc
+{
+	/// Whether a request has been seen before. If so, the caller gets the response as soon as there is one.
+	///
+	pub(crate) fn duplicate( &self, msg: &IpcMessage, ipc_peer: &Recipient< IpcMessage > ) -> bool
+	{
+		if !self.at_most_once.contains_key( &msg.service ) { return false; }
+
+		if let Some( seen ) = self.seen.lock().get_mut( &seen_key( msg ) )
+		{
+			debug!( self.log, "Rpc: duplicate request for service: {}", &msg.service );
+
+			match &seen.response
+			{
+				Some( response ) => { let _ = ipc_peer.do_send( response.clone() ); }
+				None             => seen.waiting.push( ipc_peer.clone() ),
+			}
+
+			return true;
+		}
+
+		false
+	}
+
+
+	/// Remember a request that is going to be handled by a service that should handle it at most once.
+	///
+	pub(crate) fn remember( &self, msg: &IpcMessage )
+	{
+		if let Some( window ) = self.at_most_once.get( &msg.service )
+		{
+			self.seen.lock().insert( seen_key( msg ), Seen
+			{
+				expires : Instant::now() + *window,
+				response: None                    ,
+				waiting : Vec::new()              ,
+			});
+		}
+	}
+}
+
+
+
+#[ cfg( test ) ]
+//
+mod tests
+{
+	use super::*;
+	use crate :: { PeerInfo, PeerCredentials };
+
+
+	fn peer( uid: Option<u32> ) -> Option< PeerInfo >
+	{
+		Some( PeerInfo { id: ConnID::new(), credentials: uid.map( |uid| PeerCredentials { pid: 1, uid, gid: uid } ) } )
+	}
+
+
+	fn request( peer: Option< PeerInfo >, conn_id: ConnID ) -> IpcMessage
+	{
+		let mut msg = IpcMessage::new( "Service".to_string(), (), MessageType::IpcRequestIn, conn_id );
+		msg.peer    = peer;
+		msg
+	}
+
+
+	fn unseen() -> Seen
+	{
+		Seen { expires: Instant::now() + Duration::from_secs( 60 ), response: None, waiting: Vec::new() }
+	}
+
+
+	#[ test ]
+	//
+	fn backoff_grows_up_to_the_max()
+	{
+		let policy = RetryPolicy { backoff: Duration::from_millis( 100 ), max_backoff: Duration::from_millis( 350 ), ..RetryPolicy::default() };
+
+		assert_eq!( policy.backoff( 1 ), Duration::from_millis( 100 ) );
+		assert_eq!( policy.backoff( 2 ), Duration::from_millis( 200 ) );
+		assert_eq!( policy.backoff( 3 ), Duration::from_millis( 350 ) );
+		assert_eq!( policy.backoff( 9 ), Duration::from_millis( 350 ) );
+	}
+
+
+	#[ test ]
+	//
+	fn only_transient_errors_are_retried()
+	{
+		assert!( RetryPolicy::is_transient( &EkkeIoError::ConnectionClosed                 ) );
+		assert!( RetryPolicy::is_transient( &EkkeIoError::Timeout( "Service".into() )      ) );
+		assert!( RetryPolicy::is_transient( &EkkeIoError::Overloaded( "Service".into() )   ) );
+
+		let remote = RemoteError::new( ErrorCode::Unavailable, "Service", "later" );
+		assert!( RetryPolicy::is_transient( &EkkeIoError::IpcError( remote ) ) );
+
+		assert!( !RetryPolicy::is_transient( &EkkeIoError::PermissionDenied   ( "Service".into() ) ) );
+		assert!( !RetryPolicy::is_transient( &EkkeIoError::NoHandlerForService( "Service".into() ) ) );
+
+		let remote = RemoteError::new( ErrorCode::BadRequest, "Service", "never" );
+		assert!( !RetryPolicy::is_transient( &EkkeIoError::IpcError( remote ) ) );
+	}
+
+
+	// The same conn_id from different callers are different requests, but a caller we know by uid is recognized
+	// over a new connection.
+	//
+	#[ test ]
+	//
+	fn dedup_is_per_caller()
+	{
+		let conn_id = ConnID::new();
+
+		assert_ne!( seen_key( &request( peer( None ), conn_id ) ), seen_key( &request( peer( None ), conn_id ) ) );
+		assert_ne!( seen_key( &request( peer( Some( 1 ) ), conn_id ) ), seen_key( &request( peer( Some( 2 ) ), conn_id ) ) );
+		assert_eq!( seen_key( &request( peer( Some( 1 ) ), conn_id ) ), seen_key( &request( peer( Some( 1 ) ), conn_id ) ) );
+	}
+
+
+	#[ test ]
+	//
+	fn keeps_final_answers()
+	{
+		let seen = SeenMap::default();
+		let key  = seen_key( &request( peer( Some( 1 ) ), ConnID::new() ) );
+
+		seen.lock().insert( key, unseen() );
+
+		answered( &seen, key, &IpcMessage::new( "Service".to_string(), 5, MessageType::Response, key.1 ) );
+		assert!( seen.lock()[ &key ].response.is_some() );
+
+
+		let key = seen_key( &request( peer( Some( 1 ) ), ConnID::new() ) );
+
+		seen.lock().insert( key, unseen() );
+
+		let denied = RemoteError::new( ErrorCode::PermissionDenied, "Service", "no" ).into_message( key.1 );
+
+		answered( &seen, key, &denied );
+		assert!( seen.lock()[ &key ].response.is_some() );
+	}
+
+
+	#[ test ]
+	//
+	fn forgets_transient_errors()
+	{
+		let seen = SeenMap::default();
+		let key  = seen_key( &request( peer( None ), ConnID::new() ) );
+
+		seen.lock().insert( key, unseen() );
+
+		let busy = RemoteError::from_error( "Service", &EkkeIoError::Overloaded( "Service".into() ) ).into_message( key.1 );
+
+		answered( &seen, key, &busy );
+		assert!( !seen.lock().contains_key( &key ) );
+	}
+
+
+	#[ test ]
+	//
+	fn forgets_expired_requests()
+	{
+		let now = Instant::now();
+		let old = Seen { expires: now - Duration::from_secs( 1 ), response: None, waiting: Vec::new() };
+
+		assert!( !old.keep( now ) );
+
+		let answered = Seen { response: Some( IpcMessage::new( "Service".to_string(), (), MessageType::Response, ConnID::new() ) ), ..old };
+
+		assert!( !answered.keep( now ) );
+		assert!( unseen().keep( now ) );
+	}
+}
@@ -0,0 +1,178 @@
+//! Json schemas for the messages of registered services, so clients in other languages can validate
+//! payloads and generate bindings. Only available with the `schema` feature.
+//
+use crate      :: { import::*, Rpc, IpcMessage, MessageType };
+use schemars   :: { JsonSchema, schema_for                  };
+use serde_json :: { Value, json                             };
+
+
+/// The name of the service every Rpc answers itself with the json text of [`ExportSchema`](struct.ExportSchema.html).
+/// Who may call it is decided by the same policy as for [`LIST_SERVICES`](constant.LIST_SERVICES.html).
+///
+pub const LIST_SCHEMAS: &str = "ekke_io::ListSchemas";
+
+
+
+/// Tell Rpc what the messages of a service look like. When the request message implements JsonSchema, Rpc
+/// describes it by itself when the service registers. Services answer with an IpcMessage though, so Rpc can't
+/// find out the type of the response payload by itself, you name it here.
+///
+///     rpc.do_send( DescribeService::of::< RegisterApplication, RegisterApplicationResponse >() );
+///
+#[ derive( Debug, Clone, Message ) ]
+//
+pub struct DescribeService
+{
+	pub type_id : TypeId,
+	pub request : Value ,
+	pub response: Value ,
+}
+
+
+impl DescribeService
+{
+	pub fn of<Request, Response>() -> Self
+
+		where Request : JsonSchema + 'static,
+		      Response: JsonSchema          ,
+	{
+		Self
+		{
+			type_id : TypeId::of::<Request>(),
+			request : to_value( schema_for!( Request  ) ),
+			response: to_value( schema_for!( Response ) ),
+		}
+	}
+}
+
+
+fn to_value( schema: impl Serialize ) -> Value
+{
+	// Schemas are plain data, they always serialize.
+	//
+	serde_json::to_value( schema ).unwrap_or( Value::Null )
+}
+
+
+
+/// The schema of a request message, for the messages that have one.
+///
+pub(crate) trait RequestSchema
+{
+	fn request_schema() -> Option< Value >;
+}
+
+
+impl<T> RequestSchema for T
+{
+	default fn request_schema() -> Option< Value > { None }
+}
+
+
+impl<T: JsonSchema> RequestSchema for T
+{
+	fn request_schema() -> Option< Value > { Some( to_value( schema_for!( T ) ) ) }
+}
+
+
+
+/// Ask Rpc for the schemas of all registered services. You get a json array with an object per service with
+/// the fields `name`, `version`, `request` and `response`. The schemas are null for services that haven't been
+/// described with [`DescribeService`](struct.DescribeService.html).
+///
+#[ derive( Debug, Clone, Copy, Message ) ] #[ rtype( result="Schema" ) ]
+//
+pub struct ExportSchema;
+
+
+/// The answer to ExportSchema.
+///
+#[ derive( Debug, Clone, MessageResponse ) ]
+//
+pub struct Schema( pub Value );
+
+
+
+impl Rpc
+{
+	pub(crate) fn schema( &self ) -> Schema
+	{
+		let mut services: Vec<( &String, u32, Value )> = self.handlers.iter().filter( |( _, s )| !s.members.is_empty() ).map( |( type_id, service )|
+		{
+			let described = self.schemas.get( type_id );
+
+			( &service.name, service.version, json!(
+			{
+				"name"    : service.name,
+				"version" : service.version,
+				"request" : described.map( |d| d.request .clone() ).unwrap_or( Value::Null ),
+				"response": described.map( |d| d.response.clone() ).unwrap_or( Value::Null ),
+			}))
+
+		}).collect();
+
+		services.sort_by( |a, b| ( a.0, a.1 ).cmp( &( b.0, b.1 ) ) );
+
+		Schema( Value::Array( services.into_iter().map( |( _, _, s )| s ).collect() ) )
+	}
+
+
+	/// Describe the request message of a service that registers, unless it has been described already.
+	///
+	pub(crate) fn describe<M: 'static>( &mut self )
+	{
+		let type_id = TypeId::of::<M>();
+
+		if self.schemas.contains_key( &type_id ) { return; }
+
+		if let Some( request ) = M::request_schema()
+		{
+			self.schemas.insert( type_id, DescribeService { type_id, request, response: Value::Null } );
+		}
+	}
+
+
+	/// Forget the schema of a service once nobody provides it any more.
+	///
+	pub(crate) fn forget( &mut self, type_id: &TypeId )
+	{
+		if self.handlers.get( type_id ).map( |s| s.members.is_empty() ).unwrap_or( true )
+		{
+			self.schemas.remove( type_id );
+		}
+	}
+
+
+	/// Answer a request for LIST_SCHEMAS.
+	///
+	pub(crate) fn export_schema( &self, msg: IpcMessage, ipc_peer: Recipient< IpcMessage > )
+	{
+		let text = self.schema().0.to_string();
+
+		let _ = ipc_peer.do_send( IpcMessage::new( msg.service, text, MessageType::Response, msg.conn_id ) );
+	}
+}
+
+
+
+impl Handler<DescribeService> for Rpc
+{
+	type Result = ();
+
+	fn handle( &mut self, msg: DescribeService, _ctx: &mut Context<Self> ) -> Self::Result
+	{
+		self.schemas.insert( msg.type_id, msg );
+	}
+}
+
+
+
+impl Handler<ExportSchema> for Rpc
+{
+	type Result = Schema;
+
+	fn handle( &mut self, _msg: ExportSchema, _ctx: &mut Context<Self> ) -> Self::Result
+	{
+		self.schema()
+	}
+}
@@ -0,0 +1,122 @@
+use crate :: { import::*, rpc::Policy };
+
+
+/// Sent by Rpc to itself when the actor providing a service turns out to be gone. The id makes sure we
+/// don't remove a service that has been registered again in the meantime.
+///
+#[ derive( Debug, Message ) ]
+//
+pub(crate) struct ServiceStopped
+{
+	pub(crate) type_id: TypeId,
+	pub(crate) id     : usize ,
+}
+
+
+
+/// How Rpc picks an actor from a pool of actors that provide the same service.
+///
+#[ derive( Debug, Clone, Copy, PartialEq, Eq ) ]
+//
+pub enum Balance
+{
+	/// Take turns.
+	///
+	RoundRobin,
+
+	/// The actor with the fewest requests in flight. Better when requests take very different
+	/// amounts of time.
+	///
+	LeastLoaded,
+}
+
+
+impl Default for Balance
+{
+	fn default() -> Self { Balance::RoundRobin }
+}
+
+
+
+/// What Rpc keeps for every registered service.
+///
+pub(crate) struct Service
+{
+	/// The type name of the service message.
+	///
+	pub(crate) name: String,
+
+	pub(crate) version: u32,
+
+	/// Type names of the request message and, if the service told us, of the response payload.
+	///
+	pub(crate) request_type : String,
+	pub(crate) response_type: Option< String >,
+
+	pub(crate) policy: Policy,
+
+	/// The actors that provide the service. There is only one unless the service was registered as a pool.
+	///
+	pub(crate) members: Vec< Member >,
+
+	/// None if this service doesn't take more than one actor.
+	///
+	pub(crate) pool: Option< Balance >,
+
+	/// Where round robin continues.
+	///
+	pub(crate) cursor: AtomicUsize,
+}
+
+
+/// One actor providing a service.
+///
+pub(crate) struct Member
+{
+	/// The type name of the actor that provides the service.
+	///
+	pub(crate) actor: String,
+
+	/// A `Recipient<M>` where M is the service message.
+	///
+	pub(crate) recipient: Box< dyn Any + Send >,
+
+	/// Unique for every registration.
+	///
+	pub(crate) id: usize,
+
+	/// Requests sent to this actor that haven't been answered yet.
+	///
+	pub(crate) in_flight: Arc<AtomicUsize>,
+}
+
+
+impl Service
+{
+	/// The actor that gets the next request.
+	///
+	pub(crate) fn pick( &self ) -> Option< &Member >
+	{
+		match self.pool
+		{
+			Some( Balance::LeastLoaded ) => self.members.iter().min_by_key( |m| m.in_flight.load( Ordering::SeqCst ) ),
+
+			_ =>
+			{
+				if self.members.is_empty() { return None; }
+
+				let next = self.cursor.fetch_add( 1, Ordering::SeqCst ) % self.members.len();
+
+				self.members.get( next )
+			}
+		}
+	}
+
+
+	/// Names of the actors providing this service, for logging.
+	///
+	pub(crate) fn actors( &self ) -> String
+	{
+		self.members.iter().map( |m| m.actor.as_str() ).collect::< Vec<_> >().join( ", " )
+	}
+}
@@ -0,0 +1,113 @@
+//! Running Rpc as an actix SystemService, so actors can find it in the registry instead of passing
+//! `Addr<Rpc>` around. Nothing starts unless you call `Rpc::from_registry`, you can still create your own
+//! Rpc instances, eg. for tests or to keep several networks apart.
+//
+use crate :: { import::*, Rpc, IpcMessage, EkkeIoError, RemoteError };
+
+
+/// Answers every request with `NoHandlerForService`, until the system Rpc gets configured.
+///
+fn no_services( rpc: &Rpc, _log: Logger, msg: IpcMessage, ipc_peer: Recipient< IpcMessage > )
+{
+	let error = EkkeIoError::NoHandlerForService( msg.service.clone() );
+
+	rpc.error_response( RemoteError::from_error( msg.service, &error ), ipc_peer, msg.conn_id );
+}
+
+
+/// An Rpc that discards it's logs and knows no services. Configure it with [`ConfigureRpc`](struct.ConfigureRpc.html).
+///
+impl Default for Rpc
+{
+	fn default() -> Self
+	{
+		Rpc::new( Logger::root( slog::Discard, o!() ), no_services )
+	}
+}
+
+
+impl Supervised    for Rpc {}
+impl SystemService for Rpc {}
+
+
+
+/// Set up the system Rpc. The closure gets the Rpc as it is and returns the one to use, so you can use the
+/// same builder methods as with `Rpc::new`. Only the options come from the returned Rpc, the registered services,
+/// roles, outstanding requests, statistics and what at most once services have seen are carried over, so actors
+/// that already registered don't have to do it again.
+///
+/// Concurrency and service rate limits are options, so they start afresh: buckets are full again and requests
+/// already running don't count against the new concurrency limits.
+///
+///     Rpc::from_registry().do_send( ConfigureRpc::new( move |_| Rpc::new( log, crate::service_map )
+///
+///     	.with_peer_rate_limit( RateLimit { per_second: 100.0, burst: 200 } )
+///     ));
+///
+#[ derive( Message ) ]
+//
+pub struct ConfigureRpc( Box< dyn FnOnce( Rpc ) -> Rpc + Send > );
+
+
+impl ConfigureRpc
+{
+	pub fn new( configure: impl FnOnce( Rpc ) -> Rpc + Send + 'static ) -> Self
+	{
+		ConfigureRpc( Box::new( configure ) )
+	}
+}
+
+
+impl fmt::Debug for ConfigureRpc
+{
+	fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result
+	{
+		write!( f, "ConfigureRpc" )
+	}
+}
+
+
+
+impl Handler<ConfigureRpc> for Rpc
+{
+	type Result = ();
+
+	fn handle( &mut self, msg: ConfigureRpc, _ctx: &mut Context<Self> ) -> Self::Result
+	{
+		let mut old = std::mem::replace( self, Rpc::default() );
+
+		// Take the state out, so whatever the closure returns, it doesn't get lost.
+		//
+		let handlers     = std::mem::replace( &mut old.handlers    , HashMap::new() );
+		let roles        = std::mem::replace( &mut old.roles       , HashMap::new() );
+		let peer_buckets = std::mem::replace( &mut old.peer_buckets, HashMap::new() );
+		let responses    = old.responses.clone();
+		let seen         = old.seen     .clone();
+		let addr         = old.addr     .clone();
+		let next_service = old.next_service;
+		let closing      = old.closing;
+		let stats        = old.stats.clone();
+
+		#[ cfg( feature = "schema" ) ]
+		//
+		let schemas = std::mem::replace( &mut old.schemas, HashMap::new() );
+
+		*self = ( msg.0 )( old );
+
+		self.handlers     = handlers    ;
+		self.roles        = roles       ;
+		self.peer_buckets = peer_buckets;
+		self.responses    = responses   ;
+		self.seen         = seen        ;
+		self.addr         = addr        ;
+		self.next_service = next_service;
+		self.closing      = closing     ;
+		self.stats        = stats       ;
+
+		#[ cfg( feature = "schema" ) ]
+		//
+		self.schemas = schemas;
+
+		debug!( self.log, "Rpc: configured" );
+	}
+}
@@ -0,0 +1,77 @@
+use crate :: { import::*, Rpc, IpcMessage };
+
+
+/// The versions of a service a caller can work with, both ends included. Set it on a request with
+/// [`IpcMessage::with_version`](struct.IpcMessage.html#method.with_version).
+///
+#[ derive( Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize ) ]
+//
+pub struct VersionRange
+{
+	pub min: u32,
+	pub max: Option<u32>,
+}
+
+
+impl VersionRange
+{
+	/// Exactly this version.
+	///
+	pub fn exact( version: u32 ) -> Self
+	{
+		Self { min: version, max: Some( version ) }
+	}
+
+
+	/// This version or anything newer.
+	///
+	pub fn at_least( version: u32 ) -> Self
+	{
+		Self { min: version, max: None }
+	}
+
+
+	pub fn contains( &self, version: u32 ) -> bool
+	{
+		version >= self.min && self.max.map( |max| version <= max ).unwrap_or( true )
+	}
+}
+
+
+impl fmt::Display for VersionRange
+{
+	fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result
+	{
+		match self.max
+		{
+			Some( max ) => write!( f, "{}-{}", self.min, max ),
+			None        => write!( f, "{}+"  , self.min      ),
+		}
+	}
+}
+
+
+
+/// One of the message types that can handle a versioned service, see
+/// [`Rpc::deser_versioned`](struct.Rpc.html#method.deser_versioned).
+///
+pub struct Candidate
+{
+	pub(crate) type_id: TypeId,
+	pub(crate) deser  : fn( &Rpc, IpcMessage, Recipient< IpcMessage > ),
+}
+
+
+impl Candidate
+{
+	pub fn of<INTO>() -> Self
+
+		where
+
+			INTO: DeserializeOwned + Message + Send + 'static,
+			INTO: Message< Result = IpcMessage >,
+			INTO::Result: Send,
+	{
+		Self { type_id: TypeId::of::<INTO>(), deser: Rpc::deser_into::<INTO> }
+	}
+}